@@ -3,8 +3,20 @@ fn main() {
         .expect("failed to fetch vendored protoc");
     std::env::set_var("PROTOC", protoc);
 
+    // Well-known types (google/protobuf/*.proto) ship with the vendored protoc
+    let well_known_types = protoc_bin_vendored::include_path()
+        .expect("failed to fetch vendored protoc includes");
+
     tonic_build::configure()
         .file_descriptor_set_path("proto/descriptor.bin")
-        .compile(&["proto/rate_limiter.proto"], &["proto"])
+        .compile(
+            &[
+                "proto/rate_limiter.proto",
+                "proto/google/rpc/status.proto",
+                "proto/google/rpc/error_details.proto",
+                "proto/envoy/service/ratelimit/v3/rls.proto",
+            ],
+            &["proto".as_ref(), well_known_types.as_path()],
+        )
         .expect("failed to compile protos");
 }
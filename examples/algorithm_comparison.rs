@@ -0,0 +1,86 @@
+/// Algorithm comparison - replays the same traffic against every algorithm in-process
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use rust_rate_limiter::algorithm::{Algorithm, Limit};
+use rust_rate_limiter::clock::{Clock, ManualClock};
+use rust_rate_limiter::store::{MemoryStore, RateLimitStore};
+
+/// Arrival offsets (ms) for a named traffic pattern
+fn traffic_patterns() -> Vec<(&'static str, Vec<u64>)> {
+    // Drain the quota at the end of one minute, then again right after it
+    let boundary_burst = (0..20).map(|i| 59_000 + i * 100).collect();
+
+    // One request every 2s for five minutes
+    let steady = (0..150).map(|i| i * 2_000).collect();
+
+    // Short bursts of 8 every 15s
+    let bursty = (0..20)
+        .flat_map(|burst| (0..8).map(move |i| burst * 15_000 + i * 50))
+        .collect();
+
+    vec![
+        ("boundary burst", boundary_burst),
+        ("steady 0.5 req/s", steady),
+        ("bursts of 8 every 15s", bursty),
+    ]
+}
+
+/// Largest number of admissions that fall inside any single window
+fn max_in_any_window(admitted_ms: &[u64], window: Duration) -> usize {
+    let window_ms = window.as_millis() as u64;
+    let mut span = VecDeque::new();
+    let mut max = 0;
+
+    for &at in admitted_ms {
+        span.push_back(at);
+        while let Some(&first) = span.front() {
+            if at - first < window_ms {
+                break;
+            }
+            span.pop_front();
+        }
+        max = max.max(span.len());
+    }
+
+    max
+}
+
+fn main() {
+    let base = Limit::default();
+
+    println!("⚖️  Algorithm Comparison");
+    println!("  Limit: {} per {}s", base.limit, base.window.as_secs());
+    println!();
+
+    for (name, arrivals) in traffic_patterns() {
+        println!("📈 {} ({} requests)", name, arrivals.len());
+
+        for algorithm in Algorithm::ALL {
+            let limit = Limit {
+                algorithm,
+                ..base.clone()
+            };
+            // The clock is moved to each arrival, so minutes of traffic replay instantly
+            let store = MemoryStore::default();
+            let clock = ManualClock::new();
+            let mut admitted = Vec::new();
+
+            for &offset in &arrivals {
+                clock.set(Duration::from_millis(offset));
+                if store.charge("client", std::slice::from_ref(&limit), 1, clock.now()).allowed {
+                    admitted.push(offset);
+                }
+            }
+
+            println!(
+                "  {:<24} allowed: {:>4}  max in any {}s: {:>3}",
+                algorithm.as_str(),
+                admitted.len(),
+                limit.window.as_secs(),
+                max_in_any_window(&admitted, limit.window)
+            );
+        }
+        println!();
+    }
+}
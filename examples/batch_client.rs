@@ -0,0 +1,38 @@
+use tonic::transport::Channel;
+
+pub mod rate_limiter {
+    tonic::include_proto!("rate_limiter");
+}
+
+use rate_limiter::rate_limiter_client::RateLimiterClient;
+use rate_limiter::{RateLimitBatchRequest, RateLimitRequest};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let channel = Channel::from_static("http://127.0.0.1:50051")
+        .connect()
+        .await?;
+
+    let mut client = RateLimiterClient::new(channel);
+
+    // One inbound request checked against its user, org and endpoint limits at once
+    let entries = ["user-42", "org-7", "endpoint-/v1/search"]
+        .into_iter()
+        .map(|id| RateLimitRequest {
+            id: id.to_string(),
+            tokens_requested: 1,
+            ..Default::default()
+        })
+        .collect();
+
+    let request = tonic::Request::new(RateLimitBatchRequest {
+        entries,
+        all_or_nothing: true,
+    });
+
+    let response = client.check_rate_limit_batch(request).await?;
+
+    println!("Response: {:#?}", response.into_inner());
+
+    Ok(())
+}
@@ -18,6 +18,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let request = tonic::Request::new(RateLimitRequest {
         id: "1234".to_string(),
         tokens_requested: 5,
+        ..Default::default()
     });
     
     let response = client.check_rate_limit(request).await?;
@@ -16,6 +16,7 @@ use rate_limiter::{RateLimitRequest, HeartBeatRequest};
 
 #[derive(Clone, Debug)]
 struct RequestStats {
+    #[allow(dead_code)] // only surfaced through Debug output
     id: String,
     success: bool,
     latency_ms: f64,
@@ -42,9 +43,9 @@ async fn run_load_test(
         let requests_per_thread = config.requests_per_thread;
 
         let handle = tokio::spawn(async move {
-            match Channel::from_shared(server_url).and_then(|ch| {
+            match Channel::from_shared(server_url).map(|ch| {
                 let ch = ch.connect_lazy();
-                Ok(RateLimiterClient::new(ch))
+                RateLimiterClient::new(ch)
             }) {
                 Ok(mut client) => {
                     for request_id in 0..requests_per_thread {
@@ -60,6 +61,7 @@ async fn run_load_test(
                                 let request = tonic::Request::new(RateLimitRequest {
                                     id: user_id.clone(),
                                     tokens_requested: tokens,
+                                    ..Default::default()
                                 });
 
                                 client.check_rate_limit(request).await.map(|resp| {
@@ -2,7 +2,6 @@
 /// Spawns multiple load test processes and aggregates results
 use std::process::Command;
 use std::time::Instant;
-use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -17,7 +17,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let tasks_per_channel: usize = 100;
     let requests_per_task: usize = 1000;
     
-    let total_requests = num_channels * tasks_per_channel * requests_per_task;
+    let _total_requests = num_channels * tasks_per_channel * requests_per_task;
 
     // Create channel pool
     let mut channels: Vec<Channel> = Vec::with_capacity(num_channels);
@@ -20,14 +20,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let start_time = Instant::now();
 
-    match Channel::from_shared(server_url).and_then(|ch| {
+    match Channel::from_shared(server_url).map(|ch| {
         let ch = ch.connect_lazy();
-        Ok(RateLimiterClient::new(ch))
+        RateLimiterClient::new(ch)
     }) {
         Ok(mut client) => {
             for request_id in 0..config.iteration_count {
                 let request = tonic::Request::new(HeartBeatRequest {});
-                let result = client
+                let _result = client
                     .heart_beat(request)
                     .await
                     .map(|_| (String::from("hb"), request_id, true));
@@ -0,0 +1,122 @@
+/// Streaming load test - many checks per long-lived bidi stream, no per-call stream setup
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tonic::transport::Channel;
+
+pub mod rate_limiter {
+    tonic::include_proto!("rate_limiter");
+}
+
+use rate_limiter::rate_limiter_client::RateLimiterClient;
+use rate_limiter::{RateLimitRequest, RateLimitStreamRequest};
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 16)]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Configuration - a few connections, each carrying several busy streams
+    let num_channels: usize = 16;
+    let streams_per_channel: usize = 8;
+    let requests_per_stream: usize = 50_000;
+
+    let total_requests = num_channels * streams_per_channel * requests_per_stream;
+
+    println!("🚀 Streaming gRPC Load Test");
+    println!("   Channels (connections): {}", num_channels);
+    println!("   Streams per channel: {}", streams_per_channel);
+    println!("   Requests per stream: {}", requests_per_stream);
+    println!("   Total requests: {}", total_requests);
+    println!();
+
+    let mut channels: Vec<Channel> = Vec::with_capacity(num_channels);
+    for _ in 0..num_channels {
+        let channel = Channel::from_static("http://127.0.0.1:50051")
+            .http2_adaptive_window(true)
+            .tcp_nodelay(true)
+            .connect()
+            .await?;
+        channels.push(channel);
+    }
+
+    println!("✅ Created {} connections", num_channels);
+
+    let allowed_count = Arc::new(AtomicU64::new(0));
+    let limited_count = Arc::new(AtomicU64::new(0));
+    let error_count = Arc::new(AtomicU64::new(0));
+
+    let start = Instant::now();
+
+    let mut all_tasks = Vec::with_capacity(num_channels * streams_per_channel);
+
+    for (channel_id, channel) in channels.into_iter().enumerate() {
+        for stream_id in 0..streams_per_channel {
+            let mut client = RateLimiterClient::new(channel.clone());
+            let allowed = Arc::clone(&allowed_count);
+            let limited = Arc::clone(&limited_count);
+            let errors = Arc::clone(&error_count);
+            let stream_offset = (channel_id * streams_per_channel + stream_id) * requests_per_stream;
+
+            let task = tokio::spawn(async move {
+                // HTTP/2 flow control paces the outbound side
+                let outbound = tokio_stream::iter((0..requests_per_stream).map(move |i| {
+                    let correlation_id = (stream_offset + i) as u64;
+                    RateLimitStreamRequest {
+                        correlation_id,
+                        request: Some(RateLimitRequest {
+                            id: format!("user-{}", correlation_id % 10_000),
+                            tokens_requested: 1,
+                            ..Default::default()
+                        }),
+                    }
+                }));
+
+                let mut inbound = match client.check_rate_limit_stream(outbound).await {
+                    Ok(response) => response.into_inner(),
+                    Err(_) => {
+                        errors.fetch_add(requests_per_stream as u64, Ordering::Relaxed);
+                        return;
+                    }
+                };
+
+                let (mut local_allowed, mut local_limited) = (0u64, 0u64);
+                while let Ok(Some(message)) = inbound.message().await {
+                    match message.response {
+                        Some(response) if response.status == "success" => local_allowed += 1,
+                        Some(_) => local_limited += 1,
+                        None => {
+                            errors.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+
+                allowed.fetch_add(local_allowed, Ordering::Relaxed);
+                limited.fetch_add(local_limited, Ordering::Relaxed);
+            });
+
+            all_tasks.push(task);
+        }
+    }
+
+    println!("✅ Opened {} streams", all_tasks.len());
+
+    for task in all_tasks {
+        let _ = task.await;
+    }
+
+    let elapsed = start.elapsed();
+    let total_allowed = allowed_count.load(Ordering::Relaxed);
+    let total_limited = limited_count.load(Ordering::Relaxed);
+    let total_errors = error_count.load(Ordering::Relaxed);
+    let total = total_allowed + total_limited;
+    let rps = total as f64 / elapsed.as_secs_f64();
+
+    println!();
+    println!("📊 Streaming Load Test Results");
+    println!("  Decisions received: {}", total);
+    println!("  Allowed: {}", total_allowed);
+    println!("  Rate limited: {}", total_limited);
+    println!("  Errors: {}", total_errors);
+    println!("  Duration: {:.2}s", elapsed.as_secs_f64());
+    println!("  Throughput: {:.0} req/s", rps);
+
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use tonic::{Request, Response, Status};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{reload, Registry};
+
+use rust_rate_limiter::decision_log::{DecisionLog, DecisionLogMode};
+
+use crate::rate_limiter::admin_server::Admin;
+use crate::rate_limiter::{LoggingConfig, SetLoggingRequest};
+
+/// Runtime controls for the server's logging
+pub struct AdminService {
+    // Swaps the level filter installed in front of the log output
+    log_level: reload::Handle<LevelFilter, Registry>,
+    decision_log: Arc<DecisionLog>,
+}
+
+impl AdminService {
+    pub fn new(log_level: reload::Handle<LevelFilter, Registry>, decision_log: Arc<DecisionLog>) -> Self {
+        Self {
+            log_level,
+            decision_log,
+        }
+    }
+
+    fn logging_config(&self) -> Result<LoggingConfig, Status> {
+        let level = self
+            .log_level
+            .clone_current()
+            .ok_or_else(|| Status::internal("log level filter is gone"))?;
+
+        Ok(LoggingConfig {
+            level: level.to_string().to_lowercase(),
+            decision_log: self.decision_log.mode().to_string(),
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn set_logging(
+        &self,
+        request: Request<SetLoggingRequest>,
+    ) -> Result<Response<LoggingConfig>, Status> {
+        let req = request.into_inner();
+
+        // Parse both before changing either, so a bad request changes nothing
+        let level = match req.level.as_str() {
+            "" => None,
+            level => Some(
+                level
+                    .parse::<LevelFilter>()
+                    .map_err(|_| Status::invalid_argument(format!("unknown log level: {}", level)))?,
+            ),
+        };
+        let decision_log = match req.decision_log.as_str() {
+            "" => None,
+            mode => Some(mode.parse::<DecisionLogMode>().map_err(Status::invalid_argument)?),
+        };
+
+        let changed = level.is_some() || decision_log.is_some();
+        if let Some(level) = level {
+            self.log_level
+                .reload(level)
+                .map_err(|err| Status::internal(format!("failed to change log level: {}", err)))?;
+        }
+        if let Some(mode) = decision_log {
+            self.decision_log.set_mode(mode);
+        }
+
+        let config = self.logging_config()?;
+        if changed {
+            println!("🔧 Logging set to level {}, decisions {}", config.level, config.decision_log);
+        }
+        Ok(Response::new(config))
+    }
+}
@@ -0,0 +1,776 @@
+/// Rate limiting algorithms and the per-key state each one keeps
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::calendar::Calendar;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Continuous refill of `limit` tokens per `window`, up to `burst`
+    TokenBucket,
+    /// Exact: remembers every admission inside the window
+    SlidingWindowLog,
+    /// Approximate: the previous fixed window weighted by how much of it still overlaps
+    SlidingWindowCounter,
+    /// Generic cell rate algorithm: same decisions as a smooth token bucket, one timestamp per key
+    Gcra,
+    /// `limit` tokens per calendar period, reset for every key at the same boundary
+    Calendar,
+}
+
+impl Algorithm {
+    pub const ALL: [Algorithm; 5] = [
+        Algorithm::TokenBucket,
+        Algorithm::SlidingWindowLog,
+        Algorithm::SlidingWindowCounter,
+        Algorithm::Gcra,
+        Algorithm::Calendar,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Algorithm::TokenBucket => "token_bucket",
+            Algorithm::SlidingWindowLog => "sliding_window_log",
+            Algorithm::SlidingWindowCounter => "sliding_window_counter",
+            Algorithm::Gcra => "gcra",
+            Algorithm::Calendar => "calendar",
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Algorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.as_str() == s)
+            .ok_or_else(|| format!("unknown rate limit algorithm: {}", s))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Algorithm {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A single rate limit: `limit` tokens per `window`, enforced by `algorithm`
+#[derive(Clone, Debug)]
+pub struct Limit {
+    pub algorithm: Algorithm,
+    pub limit: u64,
+    pub window: Duration,
+    /// Token bucket and GCRA capacity; the window algorithms never admit more than `limit`
+    pub burst: u64,
+    /// Period and timezone for the calendar algorithm, which ignores `window`
+    pub calendar: Calendar,
+}
+
+impl Default for Limit {
+    fn default() -> Self {
+        // 10 tokens per minute, bursting up to 10
+        Self {
+            algorithm: Algorithm::TokenBucket,
+            limit: 10,
+            window: Duration::from_secs(60),
+            burst: 10,
+            calendar: Calendar::default(),
+        }
+    }
+}
+
+/// Per-key state, shaped by the algorithm that owns it.
+///
+/// Timestamps are nanoseconds read off the owning limiter's clock rather than
+/// `Instant`s, which keeps every variant small. The window algorithms' state
+/// is boxed so it does not inflate the others: a key costs 24 bytes inline.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum KeyState {
+    TokenBucket {
+        // Fractional tokens are kept so slow refill rates still accumulate
+        tokens: f64,
+        last_refill: u64,
+    },
+    SlidingWindowLog(Box<AdmissionLog>),
+    SlidingWindowCounter(Box<WindowCounts>),
+    Gcra {
+        // Theoretical arrival time of the next token
+        tat: u64,
+    },
+    Calendar {
+        // Start of the next period, when `used` goes back to zero
+        period_end: u64,
+        used: u64,
+    },
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AdmissionLog {
+    // (admitted at, tokens) in arrival order, plus their running total
+    admitted: VecDeque<(u64, u64)>,
+    total: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WindowCounts {
+    window_start: u64,
+    // Tokens admitted in the previous and the current fixed window
+    previous: u64,
+    current: u64,
+}
+
+impl KeyState {
+    /// The algorithm this state belongs to
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            KeyState::TokenBucket { .. } => Algorithm::TokenBucket,
+            KeyState::SlidingWindowLog(_) => Algorithm::SlidingWindowLog,
+            KeyState::SlidingWindowCounter(_) => Algorithm::SlidingWindowCounter,
+            KeyState::Gcra { .. } => Algorithm::Gcra,
+            KeyState::Calendar { .. } => Algorithm::Calendar,
+        }
+    }
+
+    /// Rewrite every timestamp with `convert`, e.g. to move it to another clock
+    pub fn map_timestamps(&mut self, mut convert: impl FnMut(u64) -> u64) {
+        match self {
+            KeyState::TokenBucket { last_refill, .. } => *last_refill = convert(*last_refill),
+            KeyState::SlidingWindowLog(log) => {
+                for (at, _) in log.admitted.iter_mut() {
+                    *at = convert(*at);
+                }
+            }
+            KeyState::SlidingWindowCounter(counts) => counts.window_start = convert(counts.window_start),
+            KeyState::Gcra { tat } => *tat = convert(*tat),
+            KeyState::Calendar { period_end, .. } => *period_end = convert(*period_end),
+        }
+    }
+}
+
+impl Limit {
+    /// Tokens added back per second by the token bucket
+    pub fn refill_rate(&self) -> f64 {
+        self.limit as f64 / self.window.as_secs_f64()
+    }
+
+    /// Nanoseconds between tokens at the sustained rate
+    pub fn emission_interval(&self) -> u64 {
+        (self.window.as_nanos() / u128::from(self.limit.max(1))) as u64
+    }
+
+    /// State for a key seen for the first time
+    pub fn new_state(&self, now: u64) -> KeyState {
+        match self.algorithm {
+            Algorithm::TokenBucket => KeyState::TokenBucket {
+                tokens: self.burst as f64,
+                last_refill: now,
+            },
+            Algorithm::SlidingWindowLog => KeyState::SlidingWindowLog(Box::default()),
+            Algorithm::SlidingWindowCounter => KeyState::SlidingWindowCounter(Box::new(WindowCounts {
+                window_start: now,
+                previous: 0,
+                current: 0,
+            })),
+            Algorithm::Gcra => KeyState::Gcra { tat: now },
+            Algorithm::Calendar => KeyState::Calendar {
+                period_end: self.calendar.next_reset(now),
+                used: 0,
+            },
+        }
+    }
+
+    /// Charge `tokens` against `state` if the limit allows it
+    pub fn try_acquire(&self, state: &mut KeyState, tokens: u64, now: u64) -> Decision {
+        match (self.algorithm, &mut *state) {
+            (
+                Algorithm::TokenBucket,
+                KeyState::TokenBucket {
+                    tokens: available,
+                    last_refill,
+                },
+            ) => {
+                // Refill in proportion to the time since the last check, up to capacity
+                let rate = self.refill_rate();
+                let capacity = self.burst as f64;
+                let elapsed = now.saturating_sub(*last_refill) as f64 / 1e9;
+                *available = (*available + elapsed * rate).min(capacity);
+                *last_refill = now;
+
+                let requested = tokens as f64;
+                let allowed = *available >= requested;
+                if allowed {
+                    *available -= requested;
+                }
+
+                Decision {
+                    allowed,
+                    layer: 0,
+                    limit: self.burst,
+                    remaining: *available as u64,
+                    reset_after: secs_at_rate(capacity - *available, rate),
+                    retry_after: if allowed {
+                        Duration::ZERO
+                    } else {
+                        secs_at_rate(requested - *available, rate)
+                    },
+                }
+            }
+            (Algorithm::SlidingWindowLog, KeyState::SlidingWindowLog(log)) => {
+                // Forget admissions that have slid out of the window
+                let window = self.window.as_nanos() as u64;
+                while let Some(&(at, cost)) = log.admitted.front() {
+                    if now.saturating_sub(at) < window {
+                        break;
+                    }
+                    log.admitted.pop_front();
+                    log.total -= cost;
+                }
+
+                // A zero-token check, as `peek` and `rescale` make, admits nothing
+                // and must not hold the window open
+                let allowed = log.total + tokens <= self.limit;
+                if allowed && tokens > 0 {
+                    log.admitted.push_back((now, tokens));
+                    log.total += tokens;
+                }
+
+                // Capacity comes back as the oldest admissions leave the window
+                let expires_in = |at: u64| Duration::from_nanos((at + window).saturating_sub(now));
+                let retry_after = if allowed {
+                    Duration::ZERO
+                } else {
+                    let mut freed = 0;
+                    log.admitted
+                        .iter()
+                        .find(|&&(_, cost)| {
+                            freed += cost;
+                            log.total - freed + tokens <= self.limit
+                        })
+                        .map_or(self.window, |&(at, _)| expires_in(at))
+                };
+
+                Decision {
+                    allowed,
+                    layer: 0,
+                    limit: self.limit,
+                    remaining: self.limit.saturating_sub(log.total),
+                    reset_after: log.admitted.back().map_or(Duration::ZERO, |&(at, _)| expires_in(at)),
+                    retry_after,
+                }
+            }
+            (Algorithm::SlidingWindowCounter, KeyState::SlidingWindowCounter(counts)) => {
+                let WindowCounts {
+                    window_start,
+                    previous,
+                    current,
+                } = &mut **counts;
+
+                // Roll the fixed windows forward to the one containing `now`
+                let window = (self.window.as_nanos() as u64).max(1);
+                let elapsed_windows = now.saturating_sub(*window_start) / window;
+                if elapsed_windows == 1 {
+                    *previous = *current;
+                    *current = 0;
+                } else if elapsed_windows > 1 {
+                    *previous = 0;
+                    *current = 0;
+                }
+                *window_start += elapsed_windows * window;
+
+                // Count the part of the previous window the sliding span still covers
+                let into_window = now.saturating_sub(*window_start) as f64;
+                let overlap = 1.0 - into_window / window as f64;
+                let estimated = *previous as f64 * overlap + *current as f64;
+
+                let allowed = estimated + tokens as f64 <= self.limit as f64;
+                if allowed {
+                    *current += tokens;
+                }
+
+                let window_end = *window_start + window;
+                let reset_at = if *current > 0 {
+                    window_end + window
+                } else if *previous > 0 {
+                    window_end
+                } else {
+                    now
+                };
+
+                // The estimate drops linearly as the previous window slides out
+                let retry_at = if allowed {
+                    now
+                } else if *current + tokens <= self.limit {
+                    let headroom = (self.limit - *current - tokens) as f64;
+                    let needed = 1.0 - headroom / *previous as f64;
+                    *window_start + (needed * window as f64) as u64
+                } else if tokens <= self.limit {
+                    let headroom = (self.limit - tokens) as f64;
+                    let needed = 1.0 - headroom / *current as f64;
+                    window_end + (needed * window as f64) as u64
+                } else {
+                    reset_at
+                };
+
+                Decision {
+                    allowed,
+                    layer: 0,
+                    limit: self.limit,
+                    remaining: (self.limit as f64 - estimated - if allowed { tokens as f64 } else { 0.0 })
+                        .max(0.0) as u64,
+                    reset_after: Duration::from_nanos(reset_at.saturating_sub(now)),
+                    retry_after: Duration::from_nanos(retry_at.saturating_sub(now)),
+                }
+            }
+            (Algorithm::Gcra, KeyState::Gcra { tat }) => {
+                // Admit if the new arrival time stays within the burst tolerance.
+                // A long window times a large request may not fit in u64
+                // nanoseconds; such a charge could not be recorded, so it is denied.
+                let interval = self.emission_interval().max(1);
+                let tolerance = interval.saturating_mul(self.burst);
+                let new_tat = interval
+                    .checked_mul(tokens)
+                    .and_then(|cost| (*tat).max(now).checked_add(cost));
+
+                // More than the burst can never fit, however long the key rests
+                let allowed = tokens <= self.burst && new_tat.is_some_and(|new_tat| new_tat - now <= tolerance);
+                if let (true, Some(new_tat)) = (allowed, new_tat) {
+                    *tat = new_tat;
+                }
+
+                let backlog = tat.saturating_sub(now);
+                Decision {
+                    allowed,
+                    layer: 0,
+                    limit: self.burst,
+                    remaining: tolerance.saturating_sub(backlog) / interval,
+                    reset_after: Duration::from_nanos(backlog),
+                    retry_after: Duration::from_nanos(match new_tat {
+                        _ if allowed => 0,
+                        Some(new_tat) => (new_tat - now).saturating_sub(tolerance),
+                        None => u64::MAX,
+                    }),
+                }
+            }
+            (Algorithm::Calendar, KeyState::Calendar { period_end, used }) => {
+                if now >= *period_end {
+                    *period_end = self.calendar.next_reset(now);
+                    *used = 0;
+                }
+
+                let allowed = *used + tokens <= self.limit;
+                if allowed {
+                    *used += tokens;
+                }
+
+                // Nothing comes back before the boundary, so both waits end there
+                let until_reset = Duration::from_nanos(*period_end - now);
+                Decision {
+                    allowed,
+                    layer: 0,
+                    limit: self.limit,
+                    remaining: self.limit.saturating_sub(*used),
+                    reset_after: until_reset,
+                    retry_after: if allowed { Duration::ZERO } else { until_reset },
+                }
+            }
+            // The key was charged under another algorithm: restored from before
+            // a rules change, or not yet reached by a reload's rescale. It keeps
+            // what it spent, measured against this limit's size.
+            _ => {
+                let previous = Limit {
+                    algorithm: state.algorithm(),
+                    ..self.clone()
+                };
+                self.convert(&previous, state, now);
+                self.try_acquire(state, tokens, now)
+            }
+        }
+    }
+
+    /// Report what `try_acquire` would decide without touching `state`.
+    /// `remaining` and `reset_after` describe the key as it is now.
+    pub fn peek(&self, state: &KeyState, tokens: u64, now: u64) -> Decision {
+        let mut scratch = state.clone();
+        let current = self.try_acquire(&mut scratch, 0, now);
+        let hypothetical = self.try_acquire(&mut scratch, tokens, now);
+
+        Decision {
+            allowed: hypothetical.allowed,
+            retry_after: hypothetical.retry_after,
+            ..current
+        }
+    }
+
+    /// True when `state` is indistinguishable from a fresh key's, so dropping it loses nothing
+    pub fn is_replenished(&self, state: &KeyState, now: u64) -> bool {
+        let window = self.window.as_nanos() as u64;
+
+        match state {
+            KeyState::TokenBucket { tokens, last_refill } => {
+                let elapsed = now.saturating_sub(*last_refill) as f64 / 1e9;
+                tokens + elapsed * self.refill_rate() >= self.burst as f64
+            }
+            KeyState::SlidingWindowLog(log) => log
+                .admitted
+                .back()
+                .is_none_or(|&(at, _)| now.saturating_sub(at) >= window),
+            // Both fixed windows have rolled past once two windows have elapsed
+            KeyState::SlidingWindowCounter(counts) => {
+                let elapsed = now.saturating_sub(counts.window_start);
+                (counts.previous == 0 && counts.current == 0) || elapsed >= 2 * window
+            }
+            KeyState::Gcra { tat } => *tat <= now,
+            KeyState::Calendar { period_end, used } => *used == 0 || *period_end <= now,
+        }
+    }
+
+    /// Carry a key's state over from `previous` to this limit, keeping what the
+    /// key has spent but never leaving it more than this limit's capacity
+    pub fn rescale(&self, previous: &Limit, state: &mut KeyState, now: u64) {
+        if state.algorithm() != self.algorithm {
+            let previous = Limit {
+                algorithm: state.algorithm(),
+                ..previous.clone()
+            };
+            self.convert(&previous, state, now);
+            return;
+        }
+
+        match state {
+            KeyState::TokenBucket { tokens, .. } => {
+                *tokens = tokens.min(self.burst as f64);
+            }
+            KeyState::Gcra { tat } => {
+                // The backlog is spent tokens measured in the old emission interval
+                let spent = tat.saturating_sub(now) as f64 / previous.emission_interval().max(1) as f64;
+                let interval = self.emission_interval();
+                let backlog = (spent * interval as f64) as u64;
+                *tat = now.saturating_add(backlog.min(interval.saturating_mul(self.burst)));
+            }
+            // A new period or timezone moves the boundary; usage so far still counts
+            KeyState::Calendar { period_end, .. } => {
+                if self.calendar != previous.calendar {
+                    *period_end = self.calendar.next_reset(now);
+                }
+            }
+            // Window algorithms compare against the limit on every check
+            KeyState::SlidingWindowLog(_) | KeyState::SlidingWindowCounter(_) => {}
+        }
+    }
+
+    /// Replace `state`, kept under `previous`, with a fresh state of this
+    /// limit's algorithm. A new algorithm cannot read the old state, so what the
+    /// key has spent under `previous` is charged to the fresh state instead.
+    fn convert(&self, previous: &Limit, state: &mut KeyState, now: u64) {
+        let before = previous.peek(state, 0, now);
+        let spent = before.limit.saturating_sub(before.remaining);
+
+        let mut converted = self.new_state(now);
+        let capacity = self.peek(&converted, 0, now).remaining;
+        self.try_acquire(&mut converted, spent.min(capacity), now);
+        *state = converted;
+    }
+
+    /// Give back `tokens` previously charged by `try_acquire`, never beyond a fresh key's quota
+    pub fn refund(&self, state: &mut KeyState, tokens: u64, now: u64) {
+        match state {
+            KeyState::TokenBucket { tokens: available, .. } => {
+                *available = (*available + tokens as f64).min(self.burst as f64);
+            }
+            KeyState::SlidingWindowLog(log) => {
+                // Undo the most recent admissions first
+                let mut left = tokens;
+                while left > 0 {
+                    let Some(last) = log.admitted.back_mut() else {
+                        break;
+                    };
+                    let undone = left.min(last.1);
+                    last.1 -= undone;
+                    log.total -= undone;
+                    left -= undone;
+                    if last.1 == 0 {
+                        log.admitted.pop_back();
+                    }
+                }
+            }
+            KeyState::SlidingWindowCounter(counts) => {
+                let from_current = tokens.min(counts.current);
+                counts.current -= from_current;
+                counts.previous = counts.previous.saturating_sub(tokens - from_current);
+            }
+            KeyState::Gcra { tat } => {
+                let credit = self.emission_interval().saturating_mul(tokens);
+                *tat = tat.saturating_sub(credit).max(now);
+            }
+            KeyState::Calendar { used, .. } => {
+                *used = used.saturating_sub(tokens);
+            }
+        }
+    }
+}
+
+/// Outcome of a check, with the quota figures clients need to back off
+#[derive(Clone, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    /// Which of the rule's layers the figures below describe
+    pub layer: usize,
+    /// Most tokens the key can hold at once
+    pub limit: u64,
+    /// Tokens still available after this check
+    pub remaining: u64,
+    /// Time until the key is back to its full quota
+    pub reset_after: Duration,
+    /// Time until a request of the same size would be admitted; zero when allowed
+    pub retry_after: Duration,
+}
+
+/// Time to accumulate `amount` tokens at `rate` tokens per second
+fn secs_at_rate(amount: f64, rate: f64) -> Duration {
+    if amount <= 0.0 {
+        Duration::ZERO
+    } else {
+        Duration::try_from_secs_f64(amount / rate).unwrap_or(Duration::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+    // Far enough from zero that no test clock reading underflows
+    const START: u64 = 1_000 * SECOND;
+
+    fn limit(algorithm: Algorithm, limit: u64, window_secs: u64) -> Limit {
+        Limit {
+            algorithm,
+            limit,
+            window: Duration::from_secs(window_secs),
+            burst: limit,
+            calendar: Calendar::default(),
+        }
+    }
+
+    /// Charge one token at a time until a charge is denied, returning how many went through
+    fn drain(limit: &Limit, state: &mut KeyState, now: u64) -> u64 {
+        let mut admitted = 0;
+        while limit.try_acquire(state, 1, now).allowed {
+            admitted += 1;
+        }
+        admitted
+    }
+
+    #[test]
+    fn token_bucket_refills_at_the_limit_rate() {
+        let limit = limit(Algorithm::TokenBucket, 10, 10);
+        let mut state = limit.new_state(START);
+        assert_eq!(drain(&limit, &mut state, START), 10);
+
+        let denied = limit.try_acquire(&mut state, 1, START);
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Duration::from_secs(1));
+
+        // One token per second comes back, never more than the burst
+        assert_eq!(drain(&limit, &mut state, START + 3 * SECOND), 3);
+        assert_eq!(drain(&limit, &mut state, START + 3600 * SECOND), 10);
+    }
+
+    #[test]
+    fn token_bucket_denies_more_than_the_burst() {
+        let limit = limit(Algorithm::TokenBucket, 10, 10);
+        let mut state = limit.new_state(START);
+
+        assert!(!limit.try_acquire(&mut state, 11, START).allowed);
+        assert!(limit.try_acquire(&mut state, 10, START).allowed);
+    }
+
+    #[test]
+    fn sliding_window_log_admits_again_once_the_window_has_passed() {
+        let limit = limit(Algorithm::SlidingWindowLog, 5, 10);
+        let mut state = limit.new_state(START);
+        assert_eq!(drain(&limit, &mut state, START), 5);
+
+        let denied = limit.try_acquire(&mut state, 1, START + 4 * SECOND);
+        assert_eq!(denied.retry_after, Duration::from_secs(6));
+        assert_eq!(drain(&limit, &mut state, START + 10 * SECOND - 1), 0);
+        assert_eq!(drain(&limit, &mut state, START + 10 * SECOND), 5);
+    }
+
+    #[test]
+    fn sliding_window_counter_weights_the_previous_window() {
+        let limit = limit(Algorithm::SlidingWindowCounter, 10, 10);
+        let mut state = limit.new_state(START);
+        assert_eq!(drain(&limit, &mut state, START), 10);
+
+        // At the boundary the previous window still counts in full
+        assert_eq!(drain(&limit, &mut state, START + 10 * SECOND), 0);
+        // Halfway through the next window, half of it has slid out
+        assert_eq!(drain(&limit, &mut state, START + 15 * SECOND), 5);
+        // Two windows on, nothing is left of either
+        assert_eq!(drain(&limit, &mut state, START + 30 * SECOND), 10);
+    }
+
+    #[test]
+    fn sliding_window_counter_is_replenished_two_windows_after_its_start() {
+        let limit = limit(Algorithm::SlidingWindowCounter, 10, 10);
+        let mut state = limit.new_state(START);
+        limit.try_acquire(&mut state, 1, START);
+
+        assert!(!limit.is_replenished(&state, START + 20 * SECOND - 1));
+        assert!(limit.is_replenished(&state, START + 20 * SECOND));
+    }
+
+    #[test]
+    fn gcra_decides_like_a_token_bucket() {
+        let bucket = limit(Algorithm::TokenBucket, 5, 1);
+        let gcra = limit(Algorithm::Gcra, 5, 1);
+        let mut bucket_state = bucket.new_state(START);
+        let mut gcra_state = gcra.new_state(START);
+
+        // Irregular gaps and sizes, clear of the exact moments a token comes back
+        let mut now = START;
+        for step in 0..500u64 {
+            now += (step * 7919 % 450) * 1_000_000 + 1;
+            let tokens = 1 + step % 3;
+            let expected = bucket.try_acquire(&mut bucket_state, tokens, now);
+            let actual = gcra.try_acquire(&mut gcra_state, tokens, now);
+            assert_eq!(actual.allowed, expected.allowed, "step {} at {}", step, now);
+            assert_eq!(actual.remaining, expected.remaining, "step {} at {}", step, now);
+        }
+    }
+
+    #[test]
+    fn gcra_saturates_instead_of_overflowing() {
+        // One token every 146 years, so the burst reaches past u64::MAX nanoseconds
+        let mut limit = limit(Algorithm::Gcra, 1, u64::MAX / SECOND / 4);
+        limit.burst = 4;
+        let mut state = limit.new_state(START);
+
+        assert!(!limit.try_acquire(&mut state, u64::MAX, START).allowed);
+        let admitted = (0..10).filter(|_| limit.try_acquire(&mut state, 1, START).allowed).count();
+        assert!(admitted <= 4, "admitted {}", admitted);
+
+        limit.refund(&mut state, u64::MAX, START);
+        assert!(matches!(state, KeyState::Gcra { tat } if tat == START));
+    }
+
+    #[test]
+    fn gcra_denies_more_than_the_burst_even_when_rested() {
+        let limit = limit(Algorithm::Gcra, 10, 10);
+        let mut state = limit.new_state(START);
+
+        assert!(!limit.try_acquire(&mut state, 11, START + 3600 * SECOND).allowed);
+    }
+
+    #[test]
+    fn refund_gives_back_what_was_charged() {
+        for algorithm in Algorithm::ALL {
+            let limit = limit(algorithm, 10, 10);
+            let mut state = limit.new_state(START);
+            assert!(limit.try_acquire(&mut state, 4, START).allowed, "{}", algorithm);
+
+            limit.refund(&mut state, 4, START);
+            assert_eq!(limit.peek(&state, 0, START).remaining, 10, "{}", algorithm);
+            // Never beyond a fresh key's quota
+            limit.refund(&mut state, 4, START);
+            assert_eq!(limit.peek(&state, 0, START).remaining, 10, "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn rescale_keeps_spent_quota_when_the_algorithm_changes() {
+        for from in Algorithm::ALL {
+            for to in Algorithm::ALL {
+                let previous = limit(from, 10, 60);
+                let current = limit(to, 10, 60);
+                let mut state = previous.new_state(START);
+                assert!(previous.try_acquire(&mut state, 8, START).allowed);
+
+                current.rescale(&previous, &mut state, START);
+                assert_eq!(state.algorithm(), to);
+                assert_eq!(current.peek(&state, 0, START).remaining, 2, "{} to {}", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn rescale_caps_a_token_bucket_at_the_new_burst() {
+        let previous = limit(Algorithm::TokenBucket, 10, 10);
+        let current = limit(Algorithm::TokenBucket, 4, 10);
+        let mut state = previous.new_state(START);
+
+        current.rescale(&previous, &mut state, START);
+        assert_eq!(current.peek(&state, 0, START).remaining, 4);
+    }
+
+    #[test]
+    fn calendar_resets_at_the_period_boundary() {
+        let limit = limit(Algorithm::Calendar, 3, 1);
+        // 2024-01-15 12:00 UTC; the day ends 12 hours later
+        let noon = 1_705_320_000 * SECOND;
+        let mut state = limit.new_state(noon);
+        assert_eq!(drain(&limit, &mut state, noon), 3);
+
+        let denied = limit.try_acquire(&mut state, 1, noon);
+        assert_eq!(denied.retry_after, Duration::from_secs(12 * 3600));
+        assert_eq!(drain(&limit, &mut state, noon + 12 * 3600 * SECOND - 1), 0);
+        assert_eq!(drain(&limit, &mut state, noon + 12 * 3600 * SECOND), 3);
+    }
+
+    #[test]
+    fn key_state_stays_small() {
+        assert_eq!(std::mem::size_of::<KeyState>(), 24);
+    }
+
+    #[test]
+    fn a_charge_converts_state_left_by_another_algorithm() {
+        let previous = limit(Algorithm::TokenBucket, 10, 10);
+        let current = limit(Algorithm::Gcra, 10, 10);
+        let mut state = previous.new_state(START);
+        assert_eq!(drain(&previous, &mut state, START), 10);
+
+        // Charged under the new rule before any rescale reached the key
+        assert!(!current.try_acquire(&mut state, 1, START).allowed);
+        assert_eq!(state.algorithm(), Algorithm::Gcra);
+        assert_eq!(drain(&current, &mut state, START + SECOND), 1);
+    }
+
+    #[test]
+    fn peek_reports_the_window_without_opening_one() {
+        let limit = limit(Algorithm::SlidingWindowLog, 5, 60);
+        let mut state = limit.new_state(START);
+
+        let fresh = limit.peek(&state, 1, START);
+        assert_eq!(fresh.reset_after, Duration::ZERO);
+        assert_eq!(fresh.remaining, 5);
+
+        limit.try_acquire(&mut state, 1, START);
+        let later = limit.peek(&state, 1, START + 20 * SECOND);
+        assert_eq!(later.reset_after, Duration::from_secs(40));
+        assert!(limit.is_replenished(&state, START + 60 * SECOND));
+    }
+
+    #[test]
+    fn converting_an_unspent_key_leaves_an_empty_log() {
+        let previous = limit(Algorithm::TokenBucket, 5, 60);
+        let current = limit(Algorithm::SlidingWindowLog, 5, 60);
+        let mut state = previous.new_state(START);
+
+        current.rescale(&previous, &mut state, START);
+        assert!(matches!(&state, KeyState::SlidingWindowLog(log) if log.admitted.is_empty()));
+        assert_eq!(current.peek(&state, 0, START).reset_after, Duration::ZERO);
+    }
+}
@@ -0,0 +1,183 @@
+/// Quota periods aligned to calendar boundaries in a timezone
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{Datelike, Days, NaiveDate, TimeZone};
+use chrono_tz::Tz;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Period {
+    /// Resets at midnight
+    Day,
+    /// Resets at midnight starting Monday
+    Week,
+    /// Resets at midnight on the first of the month
+    Month,
+}
+
+impl Period {
+    pub const ALL: [Period; 3] = [Period::Day, Period::Week, Period::Month];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Period::Day => "day",
+            Period::Week => "week",
+            Period::Month => "month",
+        }
+    }
+}
+
+impl fmt::Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Period {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Period::ALL
+            .into_iter()
+            .find(|period| period.as_str() == s)
+            .ok_or_else(|| format!("unknown quota period: {}", s))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Period {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A calendar period in a timezone; every key under it resets at the same moment
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Calendar {
+    pub period: Period,
+    pub timezone: Tz,
+}
+
+impl Default for Calendar {
+    fn default() -> Self {
+        // Daily, resetting at midnight UTC
+        Self {
+            period: Period::Day,
+            timezone: Tz::UTC,
+        }
+    }
+}
+
+impl Calendar {
+    /// The first period boundary after `now`. Clock readings are taken as
+    /// nanoseconds since the Unix epoch, which is what `SystemClock` counts.
+    pub fn next_reset(&self, now: u64) -> u64 {
+        let today = self.timezone.timestamp_nanos(now as i64).date_naive();
+
+        let next = match self.period {
+            Period::Day => today + Days::new(1),
+            Period::Week => today + Days::new(7 - u64::from(today.weekday().num_days_from_monday())),
+            Period::Month => {
+                let (year, month) = if today.month() == 12 {
+                    (today.year() + 1, 1)
+                } else {
+                    (today.year(), today.month() + 1)
+                };
+                NaiveDate::from_ymd_opt(year, month, 1).unwrap_or(today + Days::new(1))
+            }
+        };
+
+        self.start_of(next).max(now + 1)
+    }
+
+    /// The first instant of `date` in this timezone
+    fn start_of(&self, date: NaiveDate) -> u64 {
+        // Midnight can fall in a daylight saving gap; the day then starts at the
+        // first hour that exists
+        (0..24)
+            .filter_map(|hour| date.and_hms_opt(hour, 0, 0))
+            .find_map(|local| self.timezone.from_local_datetime(&local).earliest())
+            .and_then(|start| start.timestamp_nanos_opt())
+            .map_or(u64::MAX, |nanos| nanos.max(0) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn calendar(period: Period, timezone: Tz) -> Calendar {
+        Calendar { period, timezone }
+    }
+
+    /// Clock reading for a UTC date and time
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> u64 {
+        let at = NaiveDate::from_ymd_opt(year, month, day)
+            .and_then(|date| date.and_hms_opt(hour, minute, 0))
+            .unwrap();
+        at.and_utc().timestamp() as u64 * SECOND
+    }
+
+    #[test]
+    fn day_resets_at_midnight_in_its_timezone() {
+        let daily = calendar(Period::Day, Tz::UTC);
+        assert_eq!(daily.next_reset(utc(2024, 1, 15, 13, 30)), utc(2024, 1, 16, 0, 0));
+
+        // Tokyo is UTC+9 all year
+        let tokyo = calendar(Period::Day, Tz::Asia__Tokyo);
+        assert_eq!(tokyo.next_reset(utc(2024, 1, 15, 13, 30)), utc(2024, 1, 15, 15, 0));
+    }
+
+    #[test]
+    fn a_reset_is_always_after_now() {
+        let daily = calendar(Period::Day, Tz::UTC);
+        let midnight = utc(2024, 1, 16, 0, 0);
+        assert_eq!(daily.next_reset(midnight), utc(2024, 1, 17, 0, 0));
+        assert_eq!(daily.next_reset(midnight - 1), midnight);
+    }
+
+    #[test]
+    fn day_follows_daylight_saving_changes() {
+        let new_york = calendar(Period::Day, Tz::America__New_York);
+        // Clocks go forward on 2024-03-10: midnight before is EST, midnight after is EDT
+        assert_eq!(new_york.next_reset(utc(2024, 3, 9, 17, 0)), utc(2024, 3, 10, 5, 0));
+        assert_eq!(new_york.next_reset(utc(2024, 3, 10, 17, 0)), utc(2024, 3, 11, 4, 0));
+        // And back on 2024-11-03
+        assert_eq!(new_york.next_reset(utc(2024, 11, 3, 17, 0)), utc(2024, 11, 4, 5, 0));
+    }
+
+    #[test]
+    fn day_starts_at_the_first_hour_when_midnight_is_skipped() {
+        // Chile moves from 00:00 straight to 01:00 on 2024-09-08
+        let santiago = calendar(Period::Day, Tz::America__Santiago);
+        assert_eq!(santiago.next_reset(utc(2024, 9, 7, 15, 0)), utc(2024, 9, 8, 4, 0));
+    }
+
+    #[test]
+    fn week_resets_on_monday() {
+        let weekly = calendar(Period::Week, Tz::UTC);
+        // 2024-01-15 is a Monday
+        assert_eq!(weekly.next_reset(utc(2024, 1, 15, 0, 0)), utc(2024, 1, 22, 0, 0));
+        assert_eq!(weekly.next_reset(utc(2024, 1, 17, 12, 0)), utc(2024, 1, 22, 0, 0));
+        assert_eq!(weekly.next_reset(utc(2024, 1, 21, 23, 59)), utc(2024, 1, 22, 0, 0));
+    }
+
+    #[test]
+    fn month_resets_on_the_first_across_month_and_year_ends() {
+        let monthly = calendar(Period::Month, Tz::UTC);
+        assert_eq!(monthly.next_reset(utc(2024, 1, 31, 23, 59)), utc(2024, 2, 1, 0, 0));
+        assert_eq!(monthly.next_reset(utc(2024, 2, 29, 12, 0)), utc(2024, 3, 1, 0, 0));
+        assert_eq!(monthly.next_reset(utc(2023, 2, 28, 12, 0)), utc(2023, 3, 1, 0, 0));
+        assert_eq!(monthly.next_reset(utc(2024, 12, 31, 12, 0)), utc(2025, 1, 1, 0, 0));
+        assert_eq!(monthly.next_reset(utc(2024, 4, 1, 0, 0)), utc(2024, 5, 1, 0, 0));
+    }
+
+    #[test]
+    fn month_uses_the_local_date() {
+        // Still January 31st in Los Angeles when it is already February in UTC
+        let monthly = calendar(Period::Month, Tz::America__Los_Angeles);
+        assert_eq!(monthly.next_reset(utc(2024, 2, 1, 4, 0)), utc(2024, 2, 1, 8, 0));
+    }
+}
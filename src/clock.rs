@@ -0,0 +1,92 @@
+/// Time sources for the limiter, in nanoseconds since an arbitrary epoch
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where the limiter gets "now" from; all key state timestamps are read off one clock
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+/// Monotonic time, starting from the wall-clock time the clock was created.
+/// Starting there rather than at zero leaves room to restore timestamps from
+/// before a restart.
+#[derive(Clone, Debug)]
+pub struct SystemClock {
+    epoch: Instant,
+    start: u64,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            start: unix_nanos(),
+        }
+    }
+}
+
+/// Wall-clock nanoseconds since the Unix epoch
+pub fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64)
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        self.start + self.epoch.elapsed().as_nanos() as u64
+    }
+}
+
+/// A clock that only moves when told to, for replaying traffic or driving tests
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self, since_epoch: Duration) {
+        self.now.store(since_epoch.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.fetch_add(by.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::Relaxed)
+    }
+}
+
+/// Pairs a clock reading with the wall-clock time it was taken at, to convert
+/// timestamps that outlive the process to and from wall-clock time
+pub(crate) struct WallClock {
+    now: u64,
+    pub(crate) wall: u64,
+}
+
+impl WallClock {
+    pub(crate) fn at(now: u64) -> Self {
+        Self { now, wall: unix_nanos() }
+    }
+
+    pub(crate) fn wall_time(&self, at: u64) -> u64 {
+        (self.wall + at).saturating_sub(self.now)
+    }
+
+    pub(crate) fn clock_time(&self, wall: u64) -> u64 {
+        (self.now + wall).saturating_sub(self.wall)
+    }
+}
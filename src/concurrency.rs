@@ -0,0 +1,154 @@
+/// In-flight (concurrency) limiting with expiring leases
+use std::time::Duration;
+
+use crate::algorithm::Decision;
+
+/// At most `max_in_flight` leases per key, each expiring after `lease_ttl` at most
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimit {
+    pub max_in_flight: u64,
+    pub lease_ttl: Duration,
+}
+
+impl Default for ConcurrencyLimit {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 10,
+            lease_ttl: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Leases currently held by one key
+#[derive(Clone, Debug, Default)]
+pub struct Leases {
+    // (lease id, expires at) in nanoseconds since the limiter's epoch
+    held: Vec<(u64, u64)>,
+}
+
+impl Leases {
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty()
+    }
+
+    /// Forget leases whose holder never released them
+    pub fn expire(&mut self, now: u64) {
+        self.held.retain(|&(_, expires_at)| expires_at > now);
+    }
+}
+
+impl ConcurrencyLimit {
+    /// The lease length granted for a requested one: `lease_ttl` is both default and maximum
+    pub fn ttl_for(&self, requested: Duration) -> Duration {
+        if requested.is_zero() {
+            self.lease_ttl
+        } else {
+            requested.min(self.lease_ttl)
+        }
+    }
+
+    /// Hold a slot under `lease_id` for `ttl` if one is free
+    pub fn try_acquire(&self, leases: &mut Leases, lease_id: u64, ttl: Duration, now: u64) -> Decision {
+        leases.expire(now);
+
+        let allowed = (leases.held.len() as u64) < self.max_in_flight;
+        if allowed {
+            leases.held.push((lease_id, now + ttl.as_nanos() as u64));
+        }
+
+        // A slot frees up when the earliest lease expires, all of them by the latest
+        let until = |at: u64| Duration::from_nanos(at.saturating_sub(now));
+        let earliest = leases.held.iter().map(|&(_, at)| at).min();
+        let latest = leases.held.iter().map(|&(_, at)| at).max();
+
+        Decision {
+            allowed,
+            layer: 0,
+            limit: self.max_in_flight,
+            remaining: self.max_in_flight.saturating_sub(leases.held.len() as u64),
+            reset_after: latest.map_or(Duration::ZERO, until),
+            retry_after: if allowed {
+                Duration::ZERO
+            } else {
+                earliest.map_or(Duration::ZERO, until)
+            },
+        }
+    }
+
+    /// Free the slot held by `lease_id`; false if it was unknown or had already expired
+    pub fn release(&self, leases: &mut Leases, lease_id: u64, now: u64) -> bool {
+        leases.expire(now);
+
+        let before = leases.held.len();
+        leases.held.retain(|&(held_id, _)| held_id != lease_id);
+        leases.held.len() < before
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn limit(max_in_flight: u64) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            max_in_flight,
+            lease_ttl: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn leases_are_granted_up_to_the_limit() {
+        let limit = limit(2);
+        let mut leases = Leases::default();
+
+        assert!(limit.try_acquire(&mut leases, 1, Duration::from_secs(10), 0).allowed);
+        let second = limit.try_acquire(&mut leases, 2, Duration::from_secs(20), SECOND);
+        assert!(second.allowed);
+        assert_eq!(second.remaining, 0);
+        assert_eq!(second.reset_after, Duration::from_secs(20));
+
+        // A slot frees up when the first lease expires
+        let denied = limit.try_acquire(&mut leases, 3, Duration::from_secs(10), 2 * SECOND);
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Duration::from_secs(8));
+    }
+
+    #[test]
+    fn expired_leases_free_their_slot() {
+        let limit = limit(1);
+        let mut leases = Leases::default();
+        limit.try_acquire(&mut leases, 1, Duration::from_secs(10), 0);
+
+        assert!(!limit.try_acquire(&mut leases, 2, Duration::from_secs(10), 10 * SECOND - 1).allowed);
+        assert!(limit.try_acquire(&mut leases, 2, Duration::from_secs(10), 10 * SECOND).allowed);
+
+        leases.expire(20 * SECOND);
+        assert!(leases.is_empty());
+    }
+
+    #[test]
+    fn release_frees_only_a_lease_still_held() {
+        let limit = limit(1);
+        let mut leases = Leases::default();
+        limit.try_acquire(&mut leases, 1, Duration::from_secs(10), 0);
+
+        assert!(!limit.release(&mut leases, 2, 0));
+        assert!(limit.release(&mut leases, 1, 0));
+        assert!(!limit.release(&mut leases, 1, 0));
+        assert!(leases.is_empty());
+
+        // A lease that has expired was already given up
+        limit.try_acquire(&mut leases, 3, Duration::from_secs(10), 0);
+        assert!(!limit.release(&mut leases, 3, 10 * SECOND));
+    }
+
+    #[test]
+    fn lease_ttl_is_both_the_default_and_the_maximum() {
+        let limit = limit(1);
+        assert_eq!(limit.ttl_for(Duration::ZERO), Duration::from_secs(30));
+        assert_eq!(limit.ttl_for(Duration::from_secs(5)), Duration::from_secs(5));
+        assert_eq!(limit.ttl_for(Duration::from_secs(300)), Duration::from_secs(30));
+    }
+}
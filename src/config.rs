@@ -1,5 +1,15 @@
 /// Configuration module for load testing and server settings
 use std::env;
+use std::time::Duration;
+
+use chrono_tz::Tz;
+use tracing::level_filters::LevelFilter;
+
+use crate::algorithm::{Algorithm, Limit};
+use crate::calendar::{Calendar, Period};
+use crate::concurrency::ConcurrencyLimit;
+use crate::decision_log::DecisionLogMode;
+use crate::eviction::EvictionPolicy;
 
 #[derive(Clone, Debug)]
 pub struct LoadTestConfig {
@@ -59,6 +69,40 @@ impl LoadTestConfig {
 pub struct ServerConfig {
     pub bind_address: String,
     pub port: u16,
+    /// Port for the HTTP/JSON gateway on `bind_address`; off when unset
+    pub http_port: Option<u16>,
+    /// Port for the Redis protocol listener on `bind_address`; off when unset
+    pub resp_port: Option<u16>,
+    /// Port `GET /metrics` is served on at `bind_address`; off when unset
+    pub metrics_port: Option<u16>,
+    /// Port for the Admin service on `bind_address`; off when unset, and never
+    /// shared with the rate limiting services
+    pub admin_port: Option<u16>,
+    /// Limit applied to every id
+    pub limit: Limit,
+    /// In-flight lease limit applied to every id
+    pub concurrency: ConcurrencyLimit,
+    /// TOML file with per-id rules; `limit` is the default when unset
+    pub rules_path: Option<String>,
+    /// How often to check the rules file for changes; zero reloads on SIGHUP only
+    pub rules_poll_interval: Duration,
+    /// When per-key state is dropped
+    pub eviction: EvictionPolicy,
+    /// File the limiter state is saved to and restored from; no snapshots when unset
+    pub snapshot_path: Option<String>,
+    /// How often to save a snapshot; one is also saved on shutdown
+    pub snapshot_interval: Duration,
+    /// Directory for the write-ahead log; when set, an allowed check is only
+    /// answered once its charge is on disk
+    pub wal_dir: Option<String>,
+    /// Most verbose log level printed; can be changed through the Admin service
+    pub log_level: LevelFilter,
+    /// Which rate limit decisions are logged
+    pub decision_log: DecisionLogMode,
+    /// OTLP/gRPC collector spans are exported to, e.g. `http://localhost:4317`
+    pub otlp_endpoint: Option<String>,
+    /// File finished spans are appended to as JSON lines
+    pub trace_file: Option<String>,
 }
 
 impl Default for ServerConfig {
@@ -66,6 +110,22 @@ impl Default for ServerConfig {
         Self {
             bind_address: "127.0.0.1".to_string(),
             port: 50051,
+            http_port: None,
+            resp_port: None,
+            metrics_port: None,
+            admin_port: None,
+            limit: Limit::default(),
+            concurrency: ConcurrencyLimit::default(),
+            rules_path: None,
+            rules_poll_interval: Duration::from_secs(5),
+            eviction: EvictionPolicy::default(),
+            snapshot_path: None,
+            snapshot_interval: Duration::from_secs(30),
+            wal_dir: None,
+            log_level: LevelFilter::INFO,
+            decision_log: DecisionLogMode::Off,
+            otlp_endpoint: None,
+            trace_file: None,
         }
     }
 }
@@ -75,16 +135,184 @@ impl ServerConfig {
         let default_server_config = ServerConfig::default();
 
         let bind_address = env::var("BIND_ADDRESS")
-            .unwrap_or_else(|_| default_server_config.bind_address);
+            .unwrap_or(default_server_config.bind_address);
         
         let port = env::var("PORT")
             .unwrap_or_else(|_| default_server_config.port.to_string())
             .parse::<u16>()
             .unwrap_or(default_server_config.port);
-        
+
+        let http_port = env::var("HTTP_PORT")
+            .ok()
+            .and_then(|value| value.parse::<u16>().ok())
+            .filter(|&port| port > 0)
+            .or(default_server_config.http_port);
+
+        let resp_port = env::var("RESP_PORT")
+            .ok()
+            .and_then(|value| value.parse::<u16>().ok())
+            .filter(|&port| port > 0)
+            .or(default_server_config.resp_port);
+
+        let metrics_port = env::var("METRICS_PORT")
+            .ok()
+            .and_then(|value| value.parse::<u16>().ok())
+            .filter(|&port| port > 0)
+            .or(default_server_config.metrics_port);
+
+        let admin_port = env::var("ADMIN_PORT")
+            .ok()
+            .and_then(|value| value.parse::<u16>().ok())
+            .filter(|&port| port > 0)
+            .or(default_server_config.admin_port);
+
+        let default_limit = default_server_config.limit;
+
+        let algorithm = env::var("RATE_ALGORITHM")
+            .ok()
+            .and_then(|value| value.parse::<Algorithm>().ok())
+            .unwrap_or(default_limit.algorithm);
+
+        let limit = env::var("RATE_LIMIT")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(default_limit.limit);
+
+        let window = env::var("RATE_WINDOW_SECS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(default_limit.window);
+
+        let burst = env::var("BUCKET_CAPACITY")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(default_limit.burst);
+
+        let period = env::var("RATE_PERIOD")
+            .ok()
+            .and_then(|value| value.parse::<Period>().ok())
+            .unwrap_or(default_limit.calendar.period);
+
+        let timezone = env::var("RATE_TIMEZONE")
+            .ok()
+            .and_then(|value| value.parse::<Tz>().ok())
+            .unwrap_or(default_limit.calendar.timezone);
+
+        let default_concurrency = default_server_config.concurrency;
+
+        let max_in_flight = env::var("MAX_IN_FLIGHT")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(default_concurrency.max_in_flight);
+
+        let lease_ttl = env::var("LEASE_TTL_SECS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(default_concurrency.lease_ttl);
+
+        let rules_path = env::var("RULES_FILE")
+            .ok()
+            .or(default_server_config.rules_path);
+
+        let rules_poll_interval = env::var("RULES_POLL_SECS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(default_server_config.rules_poll_interval);
+
+        let default_eviction = default_server_config.eviction;
+
+        let sweep_interval = env::var("SWEEP_INTERVAL_SECS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs)
+            .unwrap_or(default_eviction.sweep_interval);
+
+        // Zero or unset leaves the TTL and key cap off
+        let idle_ttl = env::var("IDLE_TTL_SECS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs)
+            .or(default_eviction.idle_ttl);
+
+        let max_keys = env::var("MAX_KEYS")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .filter(|&keys| keys > 0)
+            .or(default_eviction.max_keys);
+
+        let wal_dir = env::var("WAL_DIR").ok().or(default_server_config.wal_dir);
+
+        // The log is compacted by snapshotting, so it always comes with one
+        let snapshot_path = env::var("SNAPSHOT_FILE")
+            .ok()
+            .or(default_server_config.snapshot_path)
+            .or_else(|| wal_dir.as_ref().map(|dir| format!("{}/snapshot.bin", dir)));
+
+        let snapshot_interval = env::var("SNAPSHOT_INTERVAL_SECS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs)
+            .unwrap_or(default_server_config.snapshot_interval);
+
+        let log_level = env::var("LOG_LEVEL")
+            .ok()
+            .and_then(|value| value.parse::<LevelFilter>().ok())
+            .unwrap_or(default_server_config.log_level);
+
+        let decision_log = env::var("DECISION_LOG")
+            .ok()
+            .and_then(|value| value.parse::<DecisionLogMode>().ok())
+            .unwrap_or(default_server_config.decision_log);
+
+        // The standard OpenTelemetry variable, so collectors' docs apply as written
+        let otlp_endpoint = env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+            .ok()
+            .filter(|endpoint| !endpoint.is_empty())
+            .or(default_server_config.otlp_endpoint);
+
+        let trace_file = env::var("TRACE_FILE")
+            .ok()
+            .filter(|path| !path.is_empty())
+            .or(default_server_config.trace_file);
+
         Self {
             bind_address,
             port,
+            http_port,
+            resp_port,
+            metrics_port,
+            admin_port,
+            limit: Limit {
+                algorithm,
+                limit,
+                window,
+                burst,
+                calendar: Calendar { period, timezone },
+            },
+            concurrency: ConcurrencyLimit {
+                max_in_flight,
+                lease_ttl,
+            },
+            rules_path,
+            rules_poll_interval,
+            eviction: EvictionPolicy {
+                sweep_interval,
+                idle_ttl,
+                max_keys,
+            },
+            snapshot_path,
+            snapshot_interval,
+            wal_dir,
+            log_level,
+            decision_log,
+            otlp_endpoint,
+            trace_file,
         }
     }
 
@@ -92,6 +320,22 @@ impl ServerConfig {
         format!("{}:{}", self.bind_address, self.port)
     }
 
+    pub fn http_socket_addr(&self) -> Option<String> {
+        self.http_port.map(|port| format!("{}:{}", self.bind_address, port))
+    }
+
+    pub fn resp_socket_addr(&self) -> Option<String> {
+        self.resp_port.map(|port| format!("{}:{}", self.bind_address, port))
+    }
+
+    pub fn metrics_socket_addr(&self) -> Option<String> {
+        self.metrics_port.map(|port| format!("{}:{}", self.bind_address, port))
+    }
+
+    pub fn admin_socket_addr(&self) -> Option<String> {
+        self.admin_port.map(|port| format!("{}:{}", self.bind_address, port))
+    }
+
     pub fn url(&self) -> String {
         format!("http://{}:{}", self.bind_address, self.port)
     }
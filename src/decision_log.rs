@@ -0,0 +1,189 @@
+/// Which rate limit decisions get logged, so logging can stay on at full load
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use rand::Rng;
+
+use crate::algorithm::Decision;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DecisionLogMode {
+    /// Log nothing
+    #[default]
+    Off,
+    /// Log a random one in every `n` decisions
+    Sampled(u64),
+    /// Log every denial and no allowed request
+    Denials,
+    /// Log at most one decision per key per interval
+    PerKey(Duration),
+}
+
+impl fmt::Display for DecisionLogMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecisionLogMode::Off => f.write_str("off"),
+            DecisionLogMode::Sampled(n) => write!(f, "sample:{}", n),
+            DecisionLogMode::Denials => f.write_str("denials"),
+            DecisionLogMode::PerKey(interval) => write!(f, "per_key:{}", interval.as_secs()),
+        }
+    }
+}
+
+impl FromStr for DecisionLogMode {
+    type Err = String;
+
+    /// `off`, `denials`, `sample:N` or `per_key:SECS`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let positive = |value: &str| value.parse::<u64>().ok().filter(|&value| value > 0);
+
+        match s.split_once(':') {
+            None if s == "off" => Some(DecisionLogMode::Off),
+            None if s == "denials" => Some(DecisionLogMode::Denials),
+            Some(("sample", n)) => positive(n).map(DecisionLogMode::Sampled),
+            Some(("per_key", secs)) => positive(secs).map(|secs| DecisionLogMode::PerKey(Duration::from_secs(secs))),
+            _ => None,
+        }
+        .ok_or_else(|| format!("unknown decision log mode: {} (expected off, denials, sample:N or per_key:SECS)", s))
+    }
+}
+
+/// Logs decisions under a mode that can be switched while the server runs
+#[derive(Default)]
+pub struct DecisionLog {
+    mode: ArcSwap<DecisionLogMode>,
+    // When each key was last logged, for `PerKey`
+    last_logged: DashMap<Box<str>, u64>,
+}
+
+impl DecisionLog {
+    pub fn new(mode: DecisionLogMode) -> Self {
+        Self {
+            mode: ArcSwap::from_pointee(mode),
+            last_logged: DashMap::new(),
+        }
+    }
+
+    pub fn mode(&self) -> DecisionLogMode {
+        **self.mode.load()
+    }
+
+    pub fn set_mode(&self, mode: DecisionLogMode) {
+        self.mode.store(mode.into());
+        self.last_logged.clear();
+    }
+
+    /// Whether a decision on `id` made at `now` should be logged. Cheap enough
+    /// to call on every request; nothing is formatted unless it returns true.
+    pub fn should_log(&self, id: &str, allowed: bool, now: u64) -> bool {
+        match **self.mode.load() {
+            DecisionLogMode::Off => false,
+            DecisionLogMode::Sampled(n) => rand::thread_rng().gen_range(0..n) == 0,
+            DecisionLogMode::Denials => !allowed,
+            DecisionLogMode::PerKey(interval) => {
+                let interval = interval.as_nanos() as u64;
+                match self.last_logged.get_mut(id) {
+                    Some(last) if now.saturating_sub(*last) < interval => false,
+                    Some(mut last) => {
+                        *last = now;
+                        true
+                    }
+                    None => {
+                        self.last_logged.insert(id.into(), now);
+                        true
+                    }
+                }
+            }
+        }
+    }
+
+    /// Emit one decision as structured `tracing` fields
+    pub fn log(&self, id: &str, rule: &str, tokens: u64, decision: &Decision) {
+        if decision.allowed {
+            tracing::info!(
+                id,
+                rule,
+                tokens,
+                limit = decision.limit,
+                remaining = decision.remaining,
+                "rate limit allowed"
+            );
+        } else {
+            tracing::warn!(
+                id,
+                rule,
+                tokens,
+                limit = decision.limit,
+                remaining = decision.remaining,
+                retry_after_ms = decision.retry_after.as_millis() as u64,
+                "rate limit exceeded"
+            );
+        }
+    }
+
+    /// Forget keys whose interval has passed; they would be logged again anyway
+    pub fn purge(&self, now: u64) {
+        if let DecisionLogMode::PerKey(interval) = self.mode() {
+            let interval = interval.as_nanos() as u64;
+            self.last_logged.retain(|_, last| now.saturating_sub(*last) < interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_parse_from_their_names() {
+        assert_eq!("off".parse(), Ok(DecisionLogMode::Off));
+        assert_eq!("denials".parse(), Ok(DecisionLogMode::Denials));
+        assert_eq!("sample:100".parse(), Ok(DecisionLogMode::Sampled(100)));
+        assert_eq!("per_key:60".parse(), Ok(DecisionLogMode::PerKey(Duration::from_secs(60))));
+    }
+
+    #[test]
+    fn modes_print_as_they_parse() {
+        for mode in [
+            DecisionLogMode::Off,
+            DecisionLogMode::Denials,
+            DecisionLogMode::Sampled(7),
+            DecisionLogMode::PerKey(Duration::from_secs(30)),
+        ] {
+            assert_eq!(mode.to_string().parse(), Ok(mode));
+        }
+    }
+
+    #[test]
+    fn malformed_modes_are_rejected() {
+        for mode in ["", "on", "Off", "sample", "sample:0", "sample:x", "per_key:", "per_key:0", "denials:1"] {
+            assert!(mode.parse::<DecisionLogMode>().is_err(), "{}", mode);
+        }
+    }
+
+    #[test]
+    fn per_key_logs_each_key_once_per_interval() {
+        let log = DecisionLog::new(DecisionLogMode::PerKey(Duration::from_secs(10)));
+        let second = 1_000_000_000;
+
+        assert!(log.should_log("a", true, 0));
+        assert!(!log.should_log("a", false, 9 * second));
+        assert!(log.should_log("b", true, 9 * second));
+        assert!(log.should_log("a", true, 10 * second));
+
+        log.purge(19 * second);
+        assert!(log.last_logged.contains_key("a"));
+        assert!(!log.last_logged.contains_key("b"));
+    }
+
+    #[test]
+    fn denials_mode_skips_allowed_decisions() {
+        let log = DecisionLog::new(DecisionLogMode::Denials);
+        assert!(!log.should_log("a", true, 0));
+        assert!(log.should_log("a", false, 0));
+        assert!(!DecisionLog::default().should_log("a", false, 0));
+    }
+}
@@ -0,0 +1,265 @@
+use tonic::{Request, Response, Status};
+
+use rust_rate_limiter::algorithm::{Algorithm, Decision, Limit};
+use rust_rate_limiter::calendar::Period;
+use rust_rate_limiter::hierarchy;
+use rust_rate_limiter::rules::Rule;
+
+use crate::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
+use crate::envoy::service::ratelimit::v3::rate_limit_response::rate_limit::Unit;
+use crate::envoy::service::ratelimit::v3::rate_limit_response::{Code, DescriptorStatus, RateLimit};
+use crate::envoy::service::ratelimit::v3::rate_limit_service_server::RateLimitService;
+use crate::envoy::service::ratelimit::v3::{RateLimitRequest, RateLimitResponse};
+use crate::rate_limiter_service::RateLimiterService;
+
+/// The key a descriptor is limited under: the domain, then each entry, as a
+/// hierarchical key such as `domain=edge/remote_address=10.0.0.1`. Rules match
+/// it like any other id, and `level` rules match the last entry's key.
+fn descriptor_key(domain: &str, descriptor: &RateLimitDescriptor) -> String {
+    let entries = descriptor.entries.iter().map(|entry| (entry.key.as_str(), entry.value.as_str()));
+    hierarchy::level_keys(std::iter::once(("domain", domain)).chain(entries))
+        .pop()
+        .unwrap_or_default()
+}
+
+/// Describe the layer the decision reports, in the units Envoy understands
+fn current_limit(rule: &Rule, decision: &Decision) -> RateLimit {
+    let layer = rule.limits.get(decision.layer);
+
+    RateLimit {
+        name: rule.name.clone(),
+        requests_per_unit: decision.limit.min(u32::MAX as u64) as u32,
+        unit: layer.map_or(Unit::Unknown, unit) as i32,
+    }
+}
+
+fn unit(limit: &Limit) -> Unit {
+    if limit.algorithm == Algorithm::Calendar {
+        return match limit.calendar.period {
+            Period::Day => Unit::Day,
+            Period::Week => Unit::Week,
+            Period::Month => Unit::Month,
+        };
+    }
+
+    match limit.window.as_secs() {
+        _ if limit.window.subsec_nanos() > 0 => Unit::Unknown,
+        1 => Unit::Second,
+        60 => Unit::Minute,
+        3600 => Unit::Hour,
+        86400 => Unit::Day,
+        _ => Unit::Unknown,
+    }
+}
+
+/// Envoy's `ShouldRateLimit`, so Envoy's rate limit filter can use this server
+/// directly. Descriptors are checked independently, as Envoy expects: each one
+/// is charged if it is under its limit, and the request is over the limit if
+/// any descriptor is.
+#[tonic::async_trait]
+impl RateLimitService for RateLimiterService {
+    async fn should_rate_limit(
+        &self,
+        request: Request<RateLimitRequest>,
+    ) -> Result<Response<RateLimitResponse>, Status> {
+        let req = request.into_inner();
+
+        if req.domain.is_empty() {
+            return Err(Status::invalid_argument("domain is required"));
+        }
+        if req.descriptors.is_empty() {
+            return Err(Status::invalid_argument("at least one descriptor is required"));
+        }
+
+        // A request without hits_addend counts once
+        let hits = req.hits_addend.max(1) as u64;
+        let entries = req
+            .descriptors
+            .iter()
+            .map(|descriptor| {
+                if descriptor.entries.is_empty() {
+                    return Err(Status::invalid_argument("every descriptor needs at least one entry"));
+                }
+                if descriptor.entries.iter().any(|entry| entry.key.is_empty() || entry.value.is_empty()) {
+                    return Err(Status::invalid_argument("every descriptor entry needs a key and a value"));
+                }
+                let hits = descriptor.hits_addend.filter(|&hits| hits > 0).unwrap_or(hits);
+                Ok((vec![descriptor_key(&req.domain, descriptor)], hits.min(i32::MAX as u64) as i32))
+            })
+            .collect::<Result<Vec<_>, Status>>()?;
+
+        let decisions = self.check_rate_limit_batch(&entries, false)?;
+        let over_limit = decisions.iter().any(|(decision, _)| !decision.allowed);
+        if decisions.iter().any(|(decision, _)| decision.allowed) {
+            self.commit().await?;
+        }
+
+        let rules = self.rules();
+        let statuses = decisions
+            .iter()
+            .zip(&entries)
+            .map(|((decision, _), (keys, _))| DescriptorStatus {
+                code: if decision.allowed { Code::Ok } else { Code::OverLimit } as i32,
+                current_limit: Some(current_limit(rules.resolve(&keys[0]), decision)),
+                limit_remaining: decision.remaining.min(u32::MAX as u64) as u32,
+                duration_until_reset: decision.reset_after.try_into().ok(),
+            })
+            .collect();
+
+        Ok(Response::new(RateLimitResponse {
+            overall_code: if over_limit { Code::OverLimit } else { Code::Ok } as i32,
+            statuses,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use rust_rate_limiter::clock::ManualClock;
+    use rust_rate_limiter::concurrency::ConcurrencyLimit;
+    use rust_rate_limiter::rules::RuleSet;
+    use rust_rate_limiter::store::MemoryStore;
+
+    use super::*;
+    use crate::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::Entry;
+
+    fn descriptor(entries: &[(&str, &str)]) -> RateLimitDescriptor {
+        RateLimitDescriptor {
+            entries: entries
+                .iter()
+                .map(|(key, value)| Entry {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            ..RateLimitDescriptor::default()
+        }
+    }
+
+    fn service(rules: &str) -> (RateLimiterService, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        clock.set(Duration::from_secs(1_000));
+        let service = RateLimiterService::new(
+            RuleSet::from_toml(rules, &Limit::default()).unwrap(),
+            ConcurrencyLimit::default(),
+            Arc::new(MemoryStore::default()),
+            clock.clone(),
+        );
+        (service, clock)
+    }
+
+    #[test]
+    fn descriptors_become_hierarchical_keys_under_the_domain() {
+        let key = descriptor_key("edge", &descriptor(&[("remote_address", "10.0.0.1"), ("path", "/login")]));
+        assert_eq!(key, "domain=edge/remote_address=10.0.0.1/path=%2Flogin");
+    }
+
+    #[test]
+    fn windows_and_periods_map_to_envoy_units() {
+        let window = |secs| Limit {
+            window: Duration::from_secs(secs),
+            ..Limit::default()
+        };
+        assert_eq!(unit(&window(1)), Unit::Second);
+        assert_eq!(unit(&window(60)), Unit::Minute);
+        assert_eq!(unit(&window(3600)), Unit::Hour);
+        assert_eq!(unit(&window(86400)), Unit::Day);
+        assert_eq!(unit(&window(90)), Unit::Unknown);
+
+        let mut monthly = Limit {
+            algorithm: Algorithm::Calendar,
+            ..Limit::default()
+        };
+        monthly.calendar.period = Period::Month;
+        assert_eq!(unit(&monthly), Unit::Month);
+    }
+
+    #[tokio::test]
+    async fn each_descriptor_is_charged_and_reported_on_its_own() {
+        let (service, _) = service(
+            r#"
+            [default]
+            limit = 10
+
+            [[rules]]
+            name = "login"
+            level = "path"
+            limit = 1
+            window_secs = 1
+            "#,
+        );
+        let request = || RateLimitRequest {
+            domain: "edge".to_string(),
+            descriptors: vec![descriptor(&[("user", "alice")]), descriptor(&[("path", "/login")])],
+            hits_addend: 0,
+        };
+
+        let first = service.should_rate_limit(Request::new(request())).await.unwrap().into_inner();
+        assert_eq!(first.overall_code, Code::Ok as i32);
+
+        let second = service.should_rate_limit(Request::new(request())).await.unwrap().into_inner();
+        assert_eq!(second.overall_code, Code::OverLimit as i32);
+        let codes: Vec<i32> = second.statuses.iter().map(|status| status.code).collect();
+        assert_eq!(codes, [Code::Ok as i32, Code::OverLimit as i32]);
+        assert_eq!(second.statuses[0].limit_remaining, 8);
+
+        let login = second.statuses[1].current_limit.as_ref().unwrap();
+        assert_eq!(login.name, "login");
+        assert_eq!(login.requests_per_unit, 1);
+        assert_eq!(login.unit, Unit::Second as i32);
+    }
+
+    #[tokio::test]
+    async fn the_reported_unit_is_the_tightest_layers_even_when_limits_match() {
+        let (service, clock) = service(
+            r#"
+            [[rules]]
+            name = "user"
+            level = "user"
+            layers = [{ limit = 10, window_secs = 1 }, { limit = 10, window_secs = 60 }]
+            "#,
+        );
+        let request = |hits_addend| RateLimitRequest {
+            domain: "edge".to_string(),
+            descriptors: vec![descriptor(&[("user", "alice")])],
+            hits_addend,
+        };
+
+        service.should_rate_limit(Request::new(request(5))).await.unwrap();
+        // The per-second layer has refilled; the per-minute one has barely started
+        clock.advance(Duration::from_secs(1));
+        let response = service.should_rate_limit(Request::new(request(1))).await.unwrap().into_inner();
+
+        let status = &response.statuses[0];
+        assert_eq!(status.limit_remaining, 4);
+        let current = status.current_limit.as_ref().unwrap();
+        assert_eq!(current.requests_per_unit, 10);
+        assert_eq!(current.unit, Unit::Minute as i32);
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_are_rejected() {
+        let (service, _) = service("");
+        for request in [
+            RateLimitRequest {
+                descriptors: vec![descriptor(&[("user", "alice")])],
+                ..RateLimitRequest::default()
+            },
+            RateLimitRequest {
+                domain: "edge".to_string(),
+                ..RateLimitRequest::default()
+            },
+            RateLimitRequest {
+                domain: "edge".to_string(),
+                descriptors: vec![descriptor(&[("user", "")])],
+                ..RateLimitRequest::default()
+            },
+        ] {
+            let status = service.should_rate_limit(Request::new(request)).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        }
+    }
+}
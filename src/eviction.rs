@@ -0,0 +1,63 @@
+/// Bounds on how much per-key state the limiter keeps
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// When the sweeper drops key state
+#[derive(Clone, Debug)]
+pub struct EvictionPolicy {
+    /// How often the background sweep runs
+    pub sweep_interval: Duration,
+    /// Also drop keys unseen for this long, even if they have not fully refilled
+    pub idle_ttl: Option<Duration>,
+    /// Most keys kept; past this the sweeper evicts the least recently used
+    pub max_keys: Option<usize>,
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        // Replenished keys are always dropped; the TTL and cap are opt-in
+        Self {
+            sweep_interval: Duration::from_secs(10),
+            idle_ttl: None,
+            max_keys: None,
+        }
+    }
+}
+
+/// Running totals of evicted keys, by reason
+#[derive(Debug, Default)]
+pub struct EvictionStats {
+    pub replenished: AtomicU64,
+    pub idle: AtomicU64,
+    pub over_capacity: AtomicU64,
+}
+
+impl EvictionStats {
+    pub fn record(&self, evicted: &Evicted) {
+        self.replenished.fetch_add(evicted.replenished, Ordering::Relaxed);
+        self.idle.fetch_add(evicted.idle, Ordering::Relaxed);
+        self.over_capacity.fetch_add(evicted.over_capacity, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Evicted {
+        Evicted {
+            replenished: self.replenished.load(Ordering::Relaxed),
+            idle: self.idle.load(Ordering::Relaxed),
+            over_capacity: self.over_capacity.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counts of evicted keys, by reason
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Evicted {
+    pub replenished: u64,
+    pub idle: u64,
+    pub over_capacity: u64,
+}
+
+impl Evicted {
+    pub fn total(&self) -> u64 {
+        self.replenished + self.idle + self.over_capacity
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use prost::Message;
+use prost_types::FileDescriptorSet;
+use tonic::codegen::http::{Request, Response};
+use tonic::codegen::{BoxFuture, Service};
+use tower::Layer;
+
+use rust_rate_limiter::metrics::Metrics;
+
+// Label for paths no served service defines, so clients cannot mint new series
+const UNKNOWN_METHOD: &str = "/unknown/unknown";
+
+/// Every `/package.Service/Method` path the encoded descriptor sets define
+pub fn method_paths(descriptor_sets: &[&[u8]]) -> Result<HashSet<String>, prost::DecodeError> {
+    let mut paths = HashSet::new();
+    for descriptor_set in descriptor_sets {
+        for file in FileDescriptorSet::decode(*descriptor_set)?.file {
+            for service in &file.service {
+                for method in &service.method {
+                    paths.insert(format!("/{}.{}/{}", file.package(), service.name(), method.name()));
+                }
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Counts every gRPC call by method and status code. A handler's error is sent
+/// as a headers-only response carrying `grpc-status`; any other response has
+/// succeeded, streams included, as far as the handler is concerned. Calls to
+/// paths outside `methods` are all counted as one unknown method.
+#[derive(Clone)]
+pub struct GrpcMetricsLayer {
+    metrics: Arc<Metrics>,
+    methods: Arc<HashSet<Arc<str>>>,
+}
+
+impl GrpcMetricsLayer {
+    pub fn new(metrics: Arc<Metrics>, methods: HashSet<String>) -> Self {
+        Self {
+            metrics,
+            methods: Arc::new(methods.into_iter().map(Arc::from).collect()),
+        }
+    }
+}
+
+impl<S> Layer<S> for GrpcMetricsLayer {
+    type Service = GrpcMetrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcMetrics {
+            inner,
+            metrics: self.metrics.clone(),
+            methods: self.methods.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct GrpcMetrics<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+    methods: Arc<HashSet<Arc<str>>>,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for GrpcMetrics<S>
+where
+    S: Service<Request<B>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ResBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        // Shares the known path's name instead of copying it for every call
+        let method = self.methods.get(request.uri().path()).cloned();
+        let metrics = self.metrics.clone();
+        let response = self.inner.call(request);
+
+        Box::pin(async move {
+            let response = response.await?;
+            let code = response
+                .headers()
+                .get("grpc-status")
+                .and_then(|code| code.to_str().ok())
+                .and_then(|code| code.parse().ok())
+                .unwrap_or(0);
+            metrics.record_rpc(method.as_deref().unwrap_or(UNKNOWN_METHOD), code);
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,116 @@
+use std::task::{Context as TaskContext, Poll};
+
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::KeyValue;
+use tonic::codegen::http::{HeaderMap, Request, Response};
+use tonic::codegen::{BoxFuture, Service};
+use tonic::Code;
+use tower::Layer;
+
+// Codes that mean the server failed rather than the caller, as the
+// OpenTelemetry gRPC conventions define them
+const SERVER_ERRORS: [Code; 6] = [
+    Code::Unknown,
+    Code::DeadlineExceeded,
+    Code::Unimplemented,
+    Code::Internal,
+    Code::Unavailable,
+    Code::DataLoss,
+];
+
+/// Opens a server span for every gRPC call, as a child of the caller's span
+/// when the request carries a W3C `traceparent`. The span's context is put in
+/// the request extensions so handlers can add attributes to it. Without a
+/// tracer provider installed the spans are no-ops.
+#[derive(Clone, Default)]
+pub struct GrpcTracingLayer;
+
+impl<S> Layer<S> for GrpcTracingLayer {
+    type Service = GrpcTracing<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcTracing { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct GrpcTracing<S> {
+    inner: S,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for GrpcTracing<S>
+where
+    S: Service<Request<B>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ResBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(request.headers()))
+        });
+
+        let path = request.uri().path().trim_start_matches('/');
+        let (service, method) = path.split_once('/').unwrap_or(("", path));
+        let tracer = global::tracer(env!("CARGO_PKG_NAME"));
+        let span = tracer
+            .span_builder(path.to_string())
+            .with_kind(SpanKind::Server)
+            .with_attributes(vec![
+                KeyValue::new("rpc.system", "grpc"),
+                KeyValue::new("rpc.service", service.to_string()),
+                KeyValue::new("rpc.method", method.to_string()),
+            ])
+            .start_with_context(&tracer, &parent);
+        let cx = parent.with_span(span);
+
+        request.extensions_mut().insert(cx.clone());
+        let response = self.inner.call(request);
+
+        Box::pin(async move {
+            let response = response.await;
+            let span = cx.span();
+            if let Ok(response) = &response {
+                // A handler's error is a headers-only response carrying `grpc-status`
+                let code = response
+                    .headers()
+                    .get("grpc-status")
+                    .and_then(|code| code.to_str().ok())
+                    .and_then(|code| code.parse::<i32>().ok())
+                    .unwrap_or(0);
+                span.set_attribute(KeyValue::new("rpc.grpc.status_code", i64::from(code)));
+
+                let code = Code::from_i32(code);
+                if SERVER_ERRORS.contains(&code) {
+                    span.set_status(Status::error(code.description()));
+                }
+            }
+            span.end();
+            response
+        })
+    }
+}
+
+/// Reads propagation fields such as `traceparent` from request headers,
+/// which is where gRPC metadata travels
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
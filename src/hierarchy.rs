@@ -0,0 +1,69 @@
+//! Keys for hierarchical limits such as organization → user → route. Each level
+//! is limited under its own key, and a request is charged to every level above it.
+
+/// The key for each level of `dimensions`, outermost first: `org=acme`, then
+/// `org=acme/user=alice`, then `org=acme/user=alice/route=%2Fv1%2Fsearch`
+pub fn level_keys<'a>(dimensions: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<String> {
+    let mut path = String::new();
+    dimensions
+        .into_iter()
+        .map(|(name, value)| {
+            if !path.is_empty() {
+                path.push('/');
+            }
+            escape_into(&mut path, name);
+            path.push('=');
+            escape_into(&mut path, value);
+            path.clone()
+        })
+        .collect()
+}
+
+/// The dimension a level key ends in, e.g. `user` for `org=acme/user=alice`
+pub fn level_name(key: &str) -> Option<&str> {
+    let last = key.rsplit('/').next()?;
+    last.split_once('=').map(|(name, _)| name)
+}
+
+// Separators inside names and values would make two paths look alike
+fn escape_into(path: &mut String, part: &str) {
+    for c in part.chars() {
+        match c {
+            '%' => path.push_str("%25"),
+            '/' => path.push_str("%2F"),
+            '=' => path.push_str("%3D"),
+            _ => path.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_keys_build_on_each_other_outermost_first() {
+        let keys = level_keys([("org", "acme"), ("user", "alice"), ("route", "/v1/search")]);
+        assert_eq!(
+            keys,
+            ["org=acme", "org=acme/user=alice", "org=acme/user=alice/route=%2Fv1%2Fsearch"]
+        );
+    }
+
+    #[test]
+    fn separators_inside_names_and_values_are_escaped() {
+        // Unescaped, these two would both be `a=b=c/d`
+        let one = level_keys([("a", "b=c/d")]);
+        let other = level_keys([("a", "b"), ("c/d", "x")]);
+        assert_eq!(one, ["a=b%3Dc%2Fd"]);
+        assert_ne!(one[0], other[1]);
+        assert_eq!(level_keys([("100%", "x")]), ["100%25=x"]);
+    }
+
+    #[test]
+    fn level_name_is_the_last_dimension() {
+        assert_eq!(level_name("org=acme"), Some("org"));
+        assert_eq!(level_name("org=acme/user=alice"), Some("user"));
+        assert_eq!(level_name("plain-id"), None);
+    }
+}
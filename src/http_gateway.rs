@@ -0,0 +1,245 @@
+use std::net::SocketAddr;
+
+use axum::extract::State;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Request, Status};
+
+use crate::google::rpc::{self, QuotaFailure};
+use crate::rate_limiter::rate_limiter_server::RateLimiter;
+use crate::rate_limiter::{Dimension, RateLimitRequest, RateLimitResponse, RefundRequest};
+use crate::rate_limiter_service::RateLimiterService;
+
+/// Body of `POST /v1/check` and `POST /v1/peek`; the fields of `RateLimitRequest`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CheckBody {
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    tokens_requested: i32,
+    #[serde(default)]
+    reserve: bool,
+    #[serde(default)]
+    dimensions: Vec<DimensionBody>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DimensionBody {
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+struct CheckReply {
+    status: String,
+    limit: u64,
+    remaining: u64,
+    reset_after_secs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_secs: Option<u64>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    reservation_id: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    limited_by: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RefundBody {
+    reservation_id: String,
+    #[serde(default)]
+    tokens: i32,
+}
+
+#[derive(Serialize)]
+struct RefundReply {
+    tokens_refunded: u64,
+}
+
+#[derive(Serialize)]
+struct ErrorReply {
+    error: String,
+}
+
+/// Serve the JSON API on `addr` until `shutdown` resolves. The routes call the
+/// same `RateLimiter` methods the gRPC server does.
+pub async fn serve(
+    service: RateLimiterService,
+    addr: SocketAddr,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let app = Router::new()
+        .route("/v1/check", post(check))
+        .route("/v1/peek", post(peek))
+        .route("/v1/refund", post(refund))
+        .with_state(service);
+
+    println!("🌐 HTTP/JSON gateway listening on {}", addr);
+    axum::Server::try_bind(&addr)?
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown)
+        .await?;
+    Ok(())
+}
+
+/// Serve `GET /metrics` alone on `addr` until `shutdown` resolves, so metrics
+/// can be scraped without exposing the JSON API
+pub async fn serve_metrics(
+    service: RateLimiterService,
+    addr: SocketAddr,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let app = Router::new().route("/metrics", get(metrics)).with_state(service);
+
+    println!("📈 Metrics listening on {}", addr);
+    axum::Server::try_bind(&addr)?
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown)
+        .await?;
+    Ok(())
+}
+
+async fn check(State(service): State<RateLimiterService>, Json(body): Json<CheckBody>) -> Response {
+    match service.check_rate_limit(Request::new(body.into())).await {
+        Ok(response) => check_reply(response.metadata(), response.get_ref()),
+        Err(status) => error_reply(status),
+    }
+}
+
+async fn peek(State(service): State<RateLimiterService>, Json(body): Json<CheckBody>) -> Response {
+    match service.peek_rate_limit(Request::new(body.into())).await {
+        Ok(response) => check_reply(response.metadata(), response.get_ref()),
+        Err(status) => error_reply(status),
+    }
+}
+
+async fn refund(State(service): State<RateLimiterService>, Json(body): Json<RefundBody>) -> Response {
+    let request = RefundRequest {
+        reservation_id: body.reservation_id,
+        tokens: body.tokens,
+    };
+
+    match service.refund_rate_limit(Request::new(request)).await {
+        Ok(response) => Json(RefundReply {
+            tokens_refunded: response.get_ref().tokens_refunded,
+        })
+        .into_response(),
+        Err(status) => error_reply(status),
+    }
+}
+
+/// Prometheus scrape target
+async fn metrics(State(service): State<RateLimiterService>) -> Response {
+    let content_type = [(header::CONTENT_TYPE, "text/plain; version=0.0.4")];
+    (content_type, service.render_metrics()).into_response()
+}
+
+impl From<CheckBody> for RateLimitRequest {
+    fn from(body: CheckBody) -> Self {
+        RateLimitRequest {
+            id: body.id,
+            tokens_requested: body.tokens_requested,
+            reserve: body.reserve,
+            dimensions: body
+                .dimensions
+                .into_iter()
+                .map(|dimension| Dimension {
+                    name: dimension.name,
+                    value: dimension.value,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// 200 for an allowed check or peek; a peek that would be denied is a 429
+fn check_reply(metadata: &MetadataMap, reply: &RateLimitResponse) -> Response {
+    let code = if reply.status == "success" {
+        StatusCode::OK
+    } else {
+        StatusCode::TOO_MANY_REQUESTS
+    };
+    let mut body = reply_from_metadata(&reply.status, metadata);
+    body.reservation_id = reply.reservation_id.clone();
+    body.limited_by = reply.limited_by.clone();
+
+    (code, rate_limit_headers(metadata), Json(body)).into_response()
+}
+
+fn error_reply(status: Status) -> Response {
+    if status.code() == Code::ResourceExhausted {
+        let mut body = reply_from_metadata("rate_limited", status.metadata());
+        body.limited_by = denied_subject(&status).unwrap_or_default();
+        return (StatusCode::TOO_MANY_REQUESTS, rate_limit_headers(status.metadata()), Json(body)).into_response();
+    }
+
+    let code = match status.code() {
+        Code::InvalidArgument => StatusCode::BAD_REQUEST,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let body = ErrorReply {
+        error: status.message().to_string(),
+    };
+    (code, Json(body)).into_response()
+}
+
+/// The numbers the service put in `x-ratelimit-*` metadata, in whole seconds
+fn reply_from_metadata(status: &str, metadata: &MetadataMap) -> CheckReply {
+    let number = |key: &str| {
+        metadata
+            .get(key)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+    };
+
+    CheckReply {
+        status: status.to_string(),
+        limit: number("x-ratelimit-limit").unwrap_or_default(),
+        remaining: number("x-ratelimit-remaining").unwrap_or_default(),
+        reset_after_secs: number("x-ratelimit-reset").unwrap_or_default(),
+        retry_after_secs: number("retry-after"),
+        reservation_id: String::new(),
+        limited_by: String::new(),
+    }
+}
+
+/// `RateLimit-Limit`, `RateLimit-Remaining`, `RateLimit-Reset` and, on
+/// denial, `Retry-After`, from the gRPC metadata
+fn rate_limit_headers(metadata: &MetadataMap) -> HeaderMap {
+    const HEADERS: [(&str, &str); 4] = [
+        ("x-ratelimit-limit", "ratelimit-limit"),
+        ("x-ratelimit-remaining", "ratelimit-remaining"),
+        ("x-ratelimit-reset", "ratelimit-reset"),
+        ("retry-after", "retry-after"),
+    ];
+
+    let mut headers = HeaderMap::new();
+    for (from, to) in HEADERS {
+        if let Some(value) = metadata.get(from).and_then(|value| HeaderValue::from_bytes(value.as_bytes()).ok()) {
+            headers.insert(HeaderName::from_static(to), value);
+        }
+    }
+    headers
+}
+
+/// The key named in the denial's QuotaFailure, which for hierarchical
+/// requests is the level that denied it
+fn denied_subject(status: &Status) -> Option<String> {
+    let details = rpc::Status::decode(status.details()).ok()?;
+    let quota_failure = details
+        .details
+        .iter()
+        .find(|detail| detail.type_url.ends_with("google.rpc.QuotaFailure"))?;
+    let violation = QuotaFailure::decode(quota_failure.value.as_slice()).ok()?.violations.pop()?;
+
+    Some(violation.subject.strip_prefix("id:").unwrap_or(&violation.subject).to_string())
+}
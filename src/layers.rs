@@ -0,0 +1,171 @@
+/// Several limits enforced on the same key at once, e.g. a per-second burst
+/// cap under a per-minute rate under a daily quota
+use std::ops::{Deref, DerefMut};
+
+use crate::algorithm::{Decision, KeyState, Limit};
+
+/// A key's states, one per layer. Most rules have a single layer, whose state
+/// is kept inline so the key needs no allocation of its own; either way this
+/// is no larger than one `KeyState`.
+#[derive(Clone, Debug)]
+pub enum States {
+    Single(KeyState),
+    Layered(Box<[KeyState]>),
+}
+
+impl From<Vec<KeyState>> for States {
+    fn from(mut states: Vec<KeyState>) -> Self {
+        match states.len() {
+            1 => States::Single(states.pop().expect("one state")),
+            _ => States::Layered(states.into_boxed_slice()),
+        }
+    }
+}
+
+impl Deref for States {
+    type Target = [KeyState];
+
+    fn deref(&self) -> &[KeyState] {
+        match self {
+            States::Single(state) => std::slice::from_ref(state),
+            States::Layered(states) => states,
+        }
+    }
+}
+
+impl DerefMut for States {
+    fn deref_mut(&mut self) -> &mut [KeyState] {
+        match self {
+            States::Single(state) => std::slice::from_mut(state),
+            States::Layered(states) => states,
+        }
+    }
+}
+
+/// One fresh state per layer
+pub fn new_states(limits: &[Limit], now: u64) -> States {
+    match limits {
+        [limit] => States::Single(limit.new_state(now)),
+        _ => States::Layered(limits.iter().map(|limit| limit.new_state(now)).collect()),
+    }
+}
+
+/// Charge `tokens` to every layer if all of them allow it, and to none otherwise,
+/// so a denial by one layer never leaves tokens spent in another
+pub fn try_acquire(limits: &[Limit], states: &mut States, tokens: u64, now: u64) -> Decision {
+    match_layers(limits, states, now);
+
+    if let ([limit], [state]) = (limits, &mut **states) {
+        return limit.try_acquire(state, tokens, now);
+    }
+
+    let checked: Vec<Decision> = limits
+        .iter()
+        .zip(states.iter())
+        .map(|(limit, state)| limit.peek(state, tokens, now))
+        .collect();
+    if checked.iter().any(|decision| !decision.allowed) {
+        return combine(checked);
+    }
+
+    combine(
+        limits
+            .iter()
+            .zip(states.iter_mut())
+            .map(|(limit, state)| limit.try_acquire(state, tokens, now))
+            .collect(),
+    )
+}
+
+/// What `try_acquire` would decide, without touching `states`
+pub fn peek(limits: &[Limit], states: &[KeyState], tokens: u64, now: u64) -> Decision {
+    let mut scratch = States::from(states.to_vec());
+    match_layers(limits, &mut scratch, now);
+
+    combine(
+        limits
+            .iter()
+            .zip(scratch.iter())
+            .map(|(limit, state)| limit.peek(state, tokens, now))
+            .collect(),
+    )
+}
+
+/// Give `tokens` back to every layer
+pub fn refund(limits: &[Limit], states: &mut [KeyState], tokens: u64, now: u64) {
+    for (limit, state) in limits.iter().zip(states) {
+        limit.refund(state, tokens, now);
+    }
+}
+
+/// True once every layer is back to a fresh key's state
+pub fn is_replenished(limits: &[Limit], states: &[KeyState], now: u64) -> bool {
+    limits
+        .iter()
+        .zip(states)
+        .all(|(limit, state)| limit.is_replenished(state, now))
+}
+
+/// Carry states over from `previous` layers to `current` ones, layer by layer.
+/// Layers added by the new rule start fresh; layers it dropped are forgotten.
+pub fn rescale(previous: &[Limit], current: &[Limit], states: &mut States, now: u64) {
+    for ((limit, old_limit), state) in current.iter().zip(previous).zip(states.iter_mut()) {
+        limit.rescale(old_limit, state, now);
+    }
+    match_layers(current, states, now);
+}
+
+/// One state per layer, whatever the rule looked like when the key was created
+fn match_layers(limits: &[Limit], states: &mut States, now: u64) {
+    if states.len() == limits.len() {
+        return;
+    }
+
+    let mut resized = match std::mem::replace(states, States::Layered(Box::default())) {
+        States::Single(state) => vec![state],
+        States::Layered(layered) => layered.into_vec(),
+    };
+    resized.truncate(limits.len());
+    for limit in &limits[resized.len()..] {
+        resized.push(limit.new_state(now));
+    }
+    *states = resized.into();
+}
+
+/// Report the most constrained layer: the fewest tokens left, and when denied,
+/// the longest wait
+pub fn combine(decisions: Vec<Decision>) -> Decision {
+    let (layer, decision) = tightest(&decisions);
+    Decision { layer, ..decision }
+}
+
+/// `combine` for the levels of a hierarchical key. Each level has its own rule,
+/// so `layer` stays the one within the tightest level's rule.
+pub fn combine_levels(decisions: Vec<Decision>) -> Decision {
+    tightest(&decisions).1
+}
+
+/// Index of the decision with the fewest tokens left, and its figures merged
+/// with the longest waits of all of them
+fn tightest(decisions: &[Decision]) -> (usize, Decision) {
+    let (index, tightest) = decisions
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, decision)| decision.remaining)
+        .expect("a rule has at least one layer");
+
+    let decision = Decision {
+        allowed: decisions.iter().all(|decision| decision.allowed),
+        layer: tightest.layer,
+        limit: tightest.limit,
+        remaining: tightest.remaining,
+        reset_after: decisions.iter().map(|decision| decision.reset_after).max().unwrap_or_default(),
+        retry_after: decisions
+            .iter()
+            .filter(|decision| !decision.allowed)
+            .map(|decision| decision.retry_after)
+            .max()
+            .unwrap_or_default(),
+    };
+    (index, decision)
+}
@@ -1 +1,14 @@
+pub mod algorithm;
+pub mod calendar;
+pub mod clock;
+pub mod concurrency;
 pub mod config;
+pub mod decision_log;
+pub mod eviction;
+pub mod hierarchy;
+pub mod layers;
+pub mod metrics;
+pub mod rules;
+pub mod snapshot;
+pub mod store;
+pub mod wal;
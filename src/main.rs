@@ -1,16 +1,66 @@
+#![allow(clippy::result_large_err)] // tonic::Status is large and returned everywhere
+
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
 use tonic::transport::Server;
 use tonic_reflection::server::Builder as ReflectionBuilder;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::reload;
+use tracing_subscriber::util::SubscriberInitExt;
 
-mod config;
+mod admin_service;
+mod envoy_rls;
+mod grpc_metrics;
+mod grpc_tracing;
+mod http_gateway;
+mod persistence;
+mod resp;
 mod rate_limiter_service;
+mod rules_watcher;
+mod telemetry;
 
-use config::ServerConfig;
+use admin_service::AdminService;
+use grpc_metrics::GrpcMetricsLayer;
+use grpc_tracing::GrpcTracingLayer;
+use persistence::Persistence;
 use rate_limiter_service::RateLimiterService;
+use rust_rate_limiter::clock::{Clock, SystemClock};
+use rust_rate_limiter::config::ServerConfig;
+use rust_rate_limiter::rules::RuleSet;
+use rust_rate_limiter::store::{MemoryStore, RateLimitStore};
+use rust_rate_limiter::wal::Wal;
 
 pub mod rate_limiter {
     tonic::include_proto!("rate_limiter");
 }
 
+pub mod envoy {
+    pub mod extensions {
+        pub mod common {
+            pub mod ratelimit {
+                pub mod v3 {
+                    tonic::include_proto!("envoy.extensions.common.ratelimit.v3");
+                }
+            }
+        }
+    }
+    pub mod service {
+        pub mod ratelimit {
+            pub mod v3 {
+                tonic::include_proto!("envoy.service.ratelimit.v3");
+            }
+        }
+    }
+}
+
+pub mod google {
+    pub mod rpc {
+        tonic::include_proto!("google.rpc");
+    }
+}
+
+use envoy::service::ratelimit::v3::rate_limit_service_server::RateLimitServiceServer;
+use rate_limiter::admin_server::AdminServer;
 use rate_limiter::rate_limiter_server::RateLimiterServer;
 
 const DESCRIPTOR_SET: &[u8] = include_bytes!("../proto/descriptor.bin");
@@ -18,22 +68,196 @@ const DESCRIPTOR_SET: &[u8] = include_bytes!("../proto/descriptor.bin");
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let server_config = ServerConfig::from_env();
+
+    // The level sits behind a reload layer so the Admin service can change it
+    let (log_level, log_level_handle) = reload::Layer::new(server_config.log_level);
+    tracing_subscriber::registry()
+        .with(log_level)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+    let tracing_spans = telemetry::init(&server_config)?;
     let addr = server_config.socket_addr().parse()?;
-    let rate_limiter: RateLimiterService = RateLimiterService::default();
+    let rules = match &server_config.rules_path {
+        Some(path) => RuleSet::load(path, &server_config.limit)?,
+        None => RuleSet::single(server_config.limit.clone()),
+    };
+    println!("📏 Loaded {} rate limit rule(s)", rules.rule_count());
+
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock::new());
+    let wal = match &server_config.wal_dir {
+        Some(dir) => Some(Arc::new(Wal::open(dir, clock.now())?)),
+        None => None,
+    };
+
+    let mut store = MemoryStore::new(server_config.eviction.clone());
+    if let Some(wal) = &wal {
+        store = store.with_journal(wal.clone());
+    }
+    let store: Arc<dyn RateLimitStore> = Arc::new(store);
+
+    // Pick up the quota clients had already spent before the restart
+    let persistence = server_config.snapshot_path.clone().map(|snapshot_path| Persistence {
+        store: store.clone(),
+        clock: clock.clone(),
+        wal: wal.clone(),
+        snapshot_path,
+    });
+    if let Some(persistence) = &persistence {
+        persistence.restore()?;
+    }
+
+    let mut rate_limiter = RateLimiterService::new(
+        rules,
+        server_config.concurrency.clone(),
+        store,
+        clock,
+    )
+    .with_decision_log(server_config.decision_log);
+    if let Some(wal) = wal {
+        rate_limiter = rate_limiter.with_wal(wal);
+    }
+
+    // Bound memory: drop state for keys that no longer need it
+    let sweeper = rate_limiter.clone();
+    let sweep_interval = server_config.eviction.sweep_interval;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(sweep_interval);
+        loop {
+            // A new key past MAX_KEYS calls for a sweep without waiting out the interval
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = sweeper.eviction_wanted() => {}
+            }
+            // Sweeps scan every key, so keep them off the async workers
+            let sweeper = sweeper.clone();
+            if let Err(err) = tokio::task::spawn_blocking(move || sweeper.sweep()).await {
+                tracing::error!(error = %err, "sweep failed");
+            }
+        }
+    });
+
+    if let Some(persistence) = persistence.clone() {
+        tokio::spawn(persistence.run(server_config.snapshot_interval));
+    }
+
+    if let Some(path) = &server_config.rules_path {
+        let watcher = rules_watcher::watch_rules(
+            rate_limiter.clone(),
+            path.into(),
+            server_config.limit.clone(),
+            server_config.rules_poll_interval,
+        );
+        tokio::spawn(async move {
+            if let Err(err) = watcher.await {
+                eprintln!("⚠️  Rules hot-reload disabled: {}", err);
+            }
+        });
+    }
+
+    // Other listeners stop with the gRPC server, before the final snapshot
+    let (stop_listeners, listeners_stopped) = tokio::sync::watch::channel(());
+    let stopped = || {
+        let mut listeners_stopped = listeners_stopped.clone();
+        async move {
+            let _ = listeners_stopped.changed().await;
+        }
+    };
+    let mut listeners = Vec::new();
+
+    if let Some(http_addr) = server_config.http_socket_addr() {
+        let serve = http_gateway::serve(rate_limiter.clone(), http_addr.parse()?, stopped());
+        listeners.push(tokio::spawn(async move {
+            if let Err(err) = serve.await {
+                eprintln!("⚠️  HTTP gateway stopped: {}", err);
+            }
+        }));
+    }
+
+    if let Some(metrics_addr) = server_config.metrics_socket_addr() {
+        let serve = http_gateway::serve_metrics(rate_limiter.clone(), metrics_addr.parse()?, stopped());
+        listeners.push(tokio::spawn(async move {
+            if let Err(err) = serve.await {
+                eprintln!("⚠️  Metrics listener stopped: {}", err);
+            }
+        }));
+    }
+
+    if let Some(resp_addr) = server_config.resp_socket_addr() {
+        let serve = resp::serve(rate_limiter.clone(), resp_addr.parse()?, stopped());
+        listeners.push(tokio::spawn(async move {
+            if let Err(err) = serve.await {
+                eprintln!("⚠️  Redis protocol listener stopped: {}", err);
+            }
+        }));
+    }
+
+    // Admin gets a port of its own, so it can be kept off the network clients use
+    if let Some(admin_addr) = server_config.admin_socket_addr() {
+        let admin_addr = admin_addr.parse()?;
+        let reflection = ReflectionBuilder::configure()
+            .register_encoded_file_descriptor_set(DESCRIPTOR_SET)
+            .build()?;
+        let serve = Server::builder()
+            .add_service(reflection)
+            .add_service(AdminServer::new(AdminService::new(log_level_handle, rate_limiter.decision_log())))
+            .serve_with_shutdown(admin_addr, stopped());
+        println!("🔧 Admin service listening on {}", admin_addr);
+        listeners.push(tokio::spawn(async move {
+            if let Err(err) = serve.await {
+                eprintln!("⚠️  Admin service stopped: {}", err);
+            }
+        }));
+    }
 
     let reflection = ReflectionBuilder::configure()
         .register_encoded_file_descriptor_set(DESCRIPTOR_SET)
         .build()?;
 
+    let methods = grpc_metrics::method_paths(&[DESCRIPTOR_SET, tonic_reflection::pb::FILE_DESCRIPTOR_SET])?;
+
     println!("🚀 High-performance gRPC server listening on {}", addr);
 
     Server::builder()
         .concurrency_limit_per_connection(5000)
         .tcp_nodelay(true)
+        .layer(GrpcMetricsLayer::new(rate_limiter.metrics(), methods))
+        .layer(GrpcTracingLayer)
         .add_service(reflection)
+        .add_service(RateLimitServiceServer::new(rate_limiter.clone()))
         .add_service(RateLimiterServer::new(rate_limiter))
-        .serve(addr)
+        .serve_with_shutdown(addr, shutdown_signal())
         .await?;
 
+    let _ = stop_listeners.send(());
+    for listener in listeners {
+        let _ = listener.await;
+    }
+
+    // A deploy should not hand everyone a fresh quota
+    if let Some(persistence) = persistence {
+        persistence.save().await;
+    }
+    if tracing_spans {
+        telemetry::shutdown();
+    }
+
     Ok(())
 }
+
+/// Resolves on Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            eprintln!("⚠️  Cannot listen for SIGTERM: {}", err);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+    println!("🛑 Shutting down");
+}
@@ -0,0 +1,220 @@
+/// Counters and histograms for the `/metrics` endpoint, rendered in the
+/// Prometheus text format
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use dashmap::DashMap;
+
+use crate::store::RateLimitStore;
+
+// Upper bounds of the check latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.1,
+];
+
+// Names of the gRPC status codes, indexed by code
+const GRPC_CODES: [&str; 17] = [
+    "OK",
+    "CANCELLED",
+    "UNKNOWN",
+    "INVALID_ARGUMENT",
+    "DEADLINE_EXCEEDED",
+    "NOT_FOUND",
+    "ALREADY_EXISTS",
+    "PERMISSION_DENIED",
+    "RESOURCE_EXHAUSTED",
+    "FAILED_PRECONDITION",
+    "ABORTED",
+    "OUT_OF_RANGE",
+    "UNIMPLEMENTED",
+    "INTERNAL",
+    "UNAVAILABLE",
+    "DATA_LOSS",
+    "UNAUTHENTICATED",
+];
+
+#[derive(Default)]
+struct DecisionCounts {
+    allowed: AtomicU64,
+    denied: AtomicU64,
+}
+
+struct RpcCounts {
+    // Indexed by status code
+    by_code: [AtomicU64; GRPC_CODES.len()],
+}
+
+impl Default for RpcCounts {
+    fn default() -> Self {
+        Self {
+            by_code: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+struct Histogram {
+    // Not cumulative; summed up when rendered
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Everything the server counts. Updates are lock-free apart from the first
+/// decision under a new rule or the first call to a new RPC.
+#[derive(Default)]
+pub struct Metrics {
+    decisions: DashMap<Box<str>, DecisionCounts>,
+    check_latency: Histogram,
+    // Keyed by full gRPC method path
+    rpcs: DashMap<Box<str>, RpcCounts>,
+}
+
+impl Metrics {
+    /// Count one decision made under the rule named `rule`
+    pub fn record_decision(&self, rule: &str, allowed: bool) {
+        let counts = match self.decisions.get(rule) {
+            Some(counts) => counts,
+            None => self.decisions.entry(rule.into()).or_default().downgrade(),
+        };
+        let counter = if allowed { &counts.allowed } else { &counts.denied };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record how long a check took to reach its decision
+    pub fn record_check_latency(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        self.check_latency.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.check_latency
+            .sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Count one finished gRPC call to `method`, e.g. `/rate_limiter.RateLimiter/CheckRateLimit`
+    pub fn record_rpc(&self, method: &str, code: i32) {
+        let counts = match self.rpcs.get(method) {
+            Some(counts) => counts,
+            None => self.rpcs.entry(method.into()).or_default().downgrade(),
+        };
+        // Codes gRPC does not define are reported as UNKNOWN
+        let code = usize::try_from(code).ok().filter(|&code| code < GRPC_CODES.len()).unwrap_or(2);
+        counts.by_code[code].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The Prometheus text exposition of every metric, with key counts read from `store`
+    pub fn render(&self, store: &dyn RateLimitStore) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP rate_limiter_decisions_total Rate limit decisions, by rule and outcome\n");
+        out.push_str("# TYPE rate_limiter_decisions_total counter\n");
+        for entry in self.decisions.iter() {
+            for (decision, count) in [("allowed", &entry.allowed), ("denied", &entry.denied)] {
+                let _ = writeln!(
+                    out,
+                    "rate_limiter_decisions_total{{rule=\"{}\",decision=\"{}\"}} {}",
+                    escape(entry.key()),
+                    decision,
+                    count.load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        out.push_str("# HELP rate_limiter_check_duration_seconds Time taken to reach a rate limit decision\n");
+        out.push_str("# TYPE rate_limiter_check_duration_seconds histogram\n");
+        let mut cumulative = 0;
+        for (i, bucket) in self.check_latency.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let bound = LATENCY_BUCKETS.get(i).map_or("+Inf".to_string(), |bound| bound.to_string());
+            let _ = writeln!(out, "rate_limiter_check_duration_seconds_bucket{{le=\"{}\"}} {}", bound, cumulative);
+        }
+        let sum = self.check_latency.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(out, "rate_limiter_check_duration_seconds_sum {}", sum);
+        let _ = writeln!(out, "rate_limiter_check_duration_seconds_count {}", cumulative);
+
+        out.push_str("# HELP rate_limiter_keys Keys currently holding limiter state\n");
+        out.push_str("# TYPE rate_limiter_keys gauge\n");
+        let _ = writeln!(out, "rate_limiter_keys {}", store.key_count());
+
+        let evicted = store.eviction_stats();
+        out.push_str("# HELP rate_limiter_evicted_keys_total Keys evicted from the store, by reason\n");
+        out.push_str("# TYPE rate_limiter_evicted_keys_total counter\n");
+        for (reason, count) in [
+            ("replenished", evicted.replenished),
+            ("idle", evicted.idle),
+            ("over_capacity", evicted.over_capacity),
+        ] {
+            let _ = writeln!(out, "rate_limiter_evicted_keys_total{{reason=\"{}\"}} {}", reason, count);
+        }
+
+        out.push_str("# HELP grpc_server_handled_total gRPC calls completed, by method and status code\n");
+        out.push_str("# TYPE grpc_server_handled_total counter\n");
+        for entry in self.rpcs.iter() {
+            let path = entry.key();
+            let (service, method) = path.trim_start_matches('/').split_once('/').unwrap_or(("", path));
+            for (code, count) in GRPC_CODES.iter().zip(&entry.value().by_code) {
+                // Only codes the method has returned get a series
+                let count = count.load(Ordering::Relaxed);
+                if count == 0 {
+                    continue;
+                }
+                let _ = writeln!(
+                    out,
+                    "grpc_server_handled_total{{grpc_service=\"{}\",grpc_method=\"{}\",grpc_code=\"{}\"}} {}",
+                    escape(service),
+                    escape(method),
+                    code,
+                    count
+                );
+            }
+        }
+
+        out
+    }
+}
+
+// Label values may not contain raw backslashes, quotes or newlines
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn rpcs_are_counted_by_method_and_code() {
+        let metrics = Metrics::default();
+        metrics.record_rpc("/rate_limiter.RateLimiter/CheckRateLimit", 0);
+        metrics.record_rpc("/rate_limiter.RateLimiter/CheckRateLimit", 0);
+        metrics.record_rpc("/rate_limiter.RateLimiter/CheckRateLimit", 8);
+        metrics.record_rpc("/rate_limiter.RateLimiter/CheckRateLimit", 99);
+
+        let out = metrics.render(&MemoryStore::default());
+        let series = |code: &str| {
+            format!(
+                "grpc_server_handled_total{{grpc_service=\"rate_limiter.RateLimiter\",grpc_method=\"CheckRateLimit\",grpc_code=\"{}\"}}",
+                code
+            )
+        };
+        assert!(out.contains(&format!("{} 2\n", series("OK"))));
+        assert!(out.contains(&format!("{} 1\n", series("RESOURCE_EXHAUSTED"))));
+        // Codes gRPC does not define are folded into UNKNOWN
+        assert!(out.contains(&format!("{} 1\n", series("UNKNOWN"))));
+        // Codes never returned get no series
+        assert!(!out.contains(&series("NOT_FOUND")));
+    }
+}
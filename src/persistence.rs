@@ -0,0 +1,74 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use rust_rate_limiter::clock::Clock;
+use rust_rate_limiter::snapshot;
+use rust_rate_limiter::store::RateLimitStore;
+use rust_rate_limiter::wal::Wal;
+
+/// Saves the store to a snapshot file and, with a write-ahead log, drops the
+/// log segments each snapshot makes redundant
+#[derive(Clone)]
+pub struct Persistence {
+    pub store: Arc<dyn RateLimitStore>,
+    pub clock: Arc<dyn Clock>,
+    pub wal: Option<Arc<Wal>>,
+    pub snapshot_path: String,
+}
+
+impl Persistence {
+    /// Load the last snapshot, then replay whatever the log recorded after it
+    pub fn restore(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let restored = snapshot::load(self.store.as_ref(), self.clock.now(), &self.snapshot_path)?;
+        println!("💾 Restored {} key(s) from {}", restored, self.snapshot_path);
+
+        if let Some(wal) = &self.wal {
+            let replayed = wal.replay(self.store.as_ref(), self.clock.now())?;
+            println!("📜 Replayed {} write-ahead log record(s)", replayed);
+        }
+        Ok(())
+    }
+
+    /// Save a snapshot every `interval`
+    pub async fn run(self, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            self.save().await;
+        }
+    }
+
+    pub async fn save(&self) {
+        // Start a new segment first: everything before it is in the store, so
+        // the snapshot covers it
+        let first_kept = match &self.wal {
+            Some(wal) => match wal.rotate().await {
+                Ok(segment) => Some(segment),
+                Err(err) => {
+                    tracing::warn!(error = %err, "snapshot skipped, write-ahead log rotation failed");
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        let (store, clock, path) = (self.store.clone(), self.clock.clone(), self.snapshot_path.clone());
+        let saved = tokio::task::spawn_blocking(move || {
+            snapshot::save(store.as_ref(), clock.now(), &path).map_err(|err| err.to_string())
+        })
+        .await;
+
+        match saved {
+            Ok(Ok(count)) => tracing::info!(keys = count, path = %self.snapshot_path, "snapshot saved"),
+            Ok(Err(err)) => return tracing::warn!(error = %err, "snapshot failed"),
+            Err(err) => return tracing::warn!(error = %err, "snapshot task failed"),
+        }
+
+        if let (Some(wal), Some(first_kept)) = (&self.wal, first_kept) {
+            if let Err(err) = wal.remove_segments_before(first_kept) {
+                tracing::warn!(error = %err, "failed to compact write-ahead log");
+            }
+        }
+    }
+}
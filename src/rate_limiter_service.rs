@@ -1,39 +1,193 @@
+use arc_swap::{ArcSwap, Guard};
 use dashmap::DashMap;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{Context, KeyValue};
+use prost::Message;
+use prost_types::Any;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tonic::{Request, Response, Status};
+use std::time::{Duration, Instant, SystemTime};
+use tonic::metadata::MetadataMap;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Code, Request, Response, Status, Streaming};
 
+use rust_rate_limiter::algorithm::{Algorithm, Decision, Limit};
+use rust_rate_limiter::clock::{Clock, SystemClock};
+use rust_rate_limiter::concurrency::{ConcurrencyLimit, Leases};
+use rust_rate_limiter::decision_log::{DecisionLog, DecisionLogMode};
+use rust_rate_limiter::metrics::Metrics;
+use rust_rate_limiter::{hierarchy, layers};
+use rust_rate_limiter::rules::{Rule, RuleSet};
+use rust_rate_limiter::store::{MemoryStore, RateLimitStore, THROTTLE_KEY_PREFIX};
+use rust_rate_limiter::wal::Wal;
+
+use crate::google::rpc::{self, quota_failure::Violation, QuotaFailure, RetryInfo};
 use crate::rate_limiter::rate_limiter_server::RateLimiter;
-use crate::rate_limiter::{RateLimitRequest, RateLimitResponse, HeartBeatRequest, HeartBeatResponse};
+use crate::telemetry;
+use crate::rate_limiter::{
+    AcquireLeaseRequest, AcquireLeaseResponse, ReleaseLeaseRequest, ReleaseLeaseResponse,
+    HeartBeatRequest, HeartBeatResponse, RateLimitBatchRequest, RateLimitBatchResponse,
+    RateLimitRequest, RateLimitResponse, RateLimitStreamRequest, RateLimitStreamResponse,
+    RefundRequest, RefundResponse,
+};
+
+// Metrics label for charges against limits sent with the request rather than a rule
+const THROTTLE_RULE: &str = "cl.throttle";
 
-struct TokenBucket {
+// Messages of one stream in flight at once; past this, the stream waits for a
+// response to go out before reading on
+const STREAM_CONCURRENCY: usize = 64;
+
+/// Tokens charged to `id` that can be handed back once through RefundRateLimit
+struct Reservation {
+    // Every level the charge was made against
+    keys: Vec<Box<str>>,
     tokens: i32,
-    last_refill: Instant,
+    expires_at: u64,
 }
 
+#[derive(Clone)]
 pub struct RateLimiterService {
-    // Shared state across all requests
-    state: Arc<DashMap<String, TokenBucket>>,
-    // Configuration
-    tokens_per_window: i32,
-    window_duration: Duration,
+    // Per-key limiter state, shared across all requests
+    store: Arc<dyn RateLimitStore>,
+    // In-flight leases, keyed by the same ids as `store`
+    leases: Arc<DashMap<Box<str>, Leases>>,
+    // Outstanding reservations, removed when refunded so they cannot be replayed
+    reservations: Arc<DashMap<u64, Reservation>>,
+    // Key state, lease and reservation timestamps all come from this clock
+    clock: Arc<dyn Clock>,
+    // Configuration; rules are swapped atomically on reload
+    rules: Arc<ArcSwap<RuleSet>>,
+    concurrency: ConcurrencyLimit,
+    // When set, an allowed check is only answered once its charge is on disk
+    wal: Option<Arc<Wal>>,
+    metrics: Arc<Metrics>,
+    decision_log: Arc<DecisionLog>,
 }
 
 impl Default for RateLimiterService {
     fn default() -> Self {
-        Self {
-            state: Arc::new(DashMap::new()),
-            tokens_per_window: 10,                    // 10 tokens per window
-            window_duration: Duration::from_secs(60), // 1 minute window
-        }
+        Self::new(
+            RuleSet::single(Limit::default()),
+            ConcurrencyLimit::default(),
+            Arc::new(MemoryStore::default()),
+            Arc::new(SystemClock::new()),
+        )
     }
 }
 
 impl RateLimiterService {
-    fn validate_and_normalize_request(&self, req: &RateLimitRequest) -> Result<i32, Status> {
-        // Validate that id is present
-        if req.id.is_empty() {
-            return Err(Status::invalid_argument("id is required"));
+    pub fn new(
+        rules: RuleSet,
+        concurrency: ConcurrencyLimit,
+        store: Arc<dyn RateLimitStore>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            store,
+            leases: Arc::new(DashMap::new()),
+            reservations: Arc::new(DashMap::new()),
+            clock,
+            rules: Arc::new(ArcSwap::from_pointee(rules)),
+            concurrency,
+            wal: None,
+            metrics: Arc::new(Metrics::default()),
+            decision_log: Arc::new(DecisionLog::default()),
+        }
+    }
+
+    /// Hold allowed responses until `wal` has made their charges durable. The
+    /// store must record its changes to the same log.
+    pub fn with_wal(mut self, wal: Arc<Wal>) -> Self {
+        self.wal = Some(wal);
+        self
+    }
+
+    /// Log the decisions `mode` picks, rather than none
+    pub fn with_decision_log(mut self, mode: DecisionLogMode) -> Self {
+        self.decision_log = Arc::new(DecisionLog::new(mode));
+        self
+    }
+
+    /// Wait for charges made so far to reach the write-ahead log, if there is one
+    pub(crate) async fn commit(&self) -> Result<(), Status> {
+        match &self.wal {
+            Some(wal) => wal
+                .commit()
+                .await
+                .map_err(|err| Status::unavailable(format!("failed to record quota usage: {}", err))),
+            None => Ok(()),
+        }
+    }
+
+
+    /// Drop keys the store no longer needs, and forget expired leases and reservations
+    pub fn sweep(&self) {
+        let now = self.now();
+        let evicted = self.store.evict(&self.rules.load(), now);
+
+        self.leases.retain(|_, leases| {
+            leases.expire(now);
+            !leases.is_empty()
+        });
+        self.purge_expired_reservations();
+        self.decision_log.purge(now);
+
+        if evicted.total() > 0 {
+            tracing::info!(
+                replenished = evicted.replenished,
+                idle = evicted.idle,
+                over_capacity = evicted.over_capacity,
+                evicted_total = self.store.eviction_stats().total(),
+                keys = self.store.key_count(),
+                "evicted keys"
+            );
+        }
+    }
+
+    /// Resolves when the store asks for a sweep before the next one is due
+    pub async fn eviction_wanted(&self) {
+        match self.store.eviction_wanted() {
+            Some(wanted) => wanted.notified().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Switch to a new rule set. Existing keys keep what they have spent, clamped
+    /// to the capacity of the rule they now fall under.
+    pub fn reload_rules(&self, rules: RuleSet) {
+        let previous = self.rules.swap(Arc::new(rules));
+        self.store.rescale(&previous, &self.rules.load(), self.now());
+    }
+
+    /// The keys to charge, outermost level first, and the token count
+    fn validate_and_normalize_request(&self, req: &RateLimitRequest) -> Result<(Vec<String>, i32), Status> {
+        // Validate that exactly one of id and dimensions is present
+        let keys = match (req.id.is_empty(), req.dimensions.is_empty()) {
+            // Every level key has an `=`, so a flat id without one can never
+            // share state with a level, nor with an Envoy descriptor
+            (false, true) if req.id.contains('=') => {
+                return Err(Status::invalid_argument("ids may not contain '='; send dimensions instead"))
+            }
+            (false, true) => vec![req.id.clone()],
+            (true, false) => {
+                if req.dimensions.iter().any(|dimension| dimension.name.is_empty() || dimension.value.is_empty()) {
+                    return Err(Status::invalid_argument("every dimension needs a name and a value"));
+                }
+                hierarchy::level_keys(
+                    req.dimensions
+                        .iter()
+                        .map(|dimension| (dimension.name.as_str(), dimension.value.as_str())),
+                )
+            }
+            (true, true) => return Err(Status::invalid_argument("id is required")),
+            (false, false) => return Err(Status::invalid_argument("set either id or dimensions, not both")),
+        };
+        if keys.iter().any(|key| key.starts_with(THROTTLE_KEY_PREFIX)) {
+            return Err(Status::invalid_argument(format!(
+                "ids starting with {} are reserved",
+                THROTTLE_KEY_PREFIX
+            )));
         }
 
         // Default tokens_requested to 1 if not provided or invalid
@@ -43,73 +197,904 @@ impl RateLimiterService {
             req.tokens_requested
         };
 
-        Ok(tokens)
+        Ok((keys, tokens))
+    }
+
+    /// Charge a request under its rules and wait until the charge is durable.
+    /// For frontends that report the decision in their own format.
+    pub(crate) async fn check(&self, req: &RateLimitRequest) -> Result<Decision, Status> {
+        let (keys, tokens) = self.validate_and_normalize_request(req)?;
+        let (decision, _) = self.check_levels(&keys, tokens)?;
+        if decision.allowed {
+            self.commit().await?;
+        }
+        Ok(decision)
     }
 
-    fn check_rate_limit(&self, id: &str, tokens_requested: i32) -> Result<bool, Status> {
-        let now = Instant::now();
+    /// Charge `id` against `limit` instead of the rule it resolves to, for
+    /// callers that send their limit with every request. The key lives apart
+    /// from rule-based ids, so neither can reset the other's state.
+    pub(crate) async fn throttle(&self, id: &str, limit: &Limit, tokens: u64) -> Result<Decision, Status> {
+        let key = format!("{}{}", THROTTLE_KEY_PREFIX, id);
+        let started = Instant::now();
+        let decision = self.store.charge(&key, std::slice::from_ref(limit), tokens, self.now());
+        self.metrics.record_check_latency(started.elapsed());
+        self.metrics.record_decision(THROTTLE_RULE, decision.allowed);
+        self.log_decision(id, THROTTLE_RULE, tokens, &decision);
 
-        // Get or insert bucket for this ID
-        let mut bucket = self
-            .state
-            .entry(id.to_string())
-            .or_insert_with(|| TokenBucket {
-                tokens: self.tokens_per_window,
-                last_refill: now,
-            });
+        if decision.allowed {
+            self.commit().await?;
+        }
+        Ok(decision)
+    }
+
+    /// Log `decision` if the decision log mode picks it
+    fn log_decision(&self, id: &str, rule: &str, tokens: u64, decision: &Decision) {
+        if self.decision_log.should_log(id, decision.allowed, self.now()) {
+            self.decision_log.log(id, rule, tokens, decision);
+        }
+    }
+
+    /// Describe a decision on the call's span. The id is the key that denied
+    /// the request, or the most specific one when it was allowed.
+    fn annotate_span(&self, span: &Context, keys: &[String], tokens: i32, decision: &Decision, denied_at: Option<usize>) {
+        let id = denied_at.map_or(leaf(keys), |level| keys[level].as_str());
+        let rules = self.rules.load();
+        span.span().set_attributes([
+            KeyValue::new("ratelimit.id", id.to_string()),
+            KeyValue::new("ratelimit.tokens", i64::from(tokens)),
+            KeyValue::new("ratelimit.decision", if decision.allowed { "allowed" } else { "denied" }),
+            KeyValue::new("ratelimit.rule", rules.resolve(id).name.clone()),
+        ]);
+    }
+
+    /// Which decisions are logged; shared with the admin service, which can change it
+    pub(crate) fn decision_log(&self) -> Arc<DecisionLog> {
+        self.decision_log.clone()
+    }
+
+    /// Counters shared by every frontend
+    pub(crate) fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Every metric in the Prometheus text format
+    pub(crate) fn render_metrics(&self) -> String {
+        self.metrics.render(self.store.as_ref())
+    }
+
+    /// The rule set currently in effect
+    pub(crate) fn rules(&self) -> Guard<Arc<RuleSet>> {
+        self.rules.load()
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Charge every level in `keys` and count the outcome in the metrics and
+    /// decision log
+    fn check_levels(&self, keys: &[String], tokens_requested: i32) -> Result<(Decision, Option<usize>), Status> {
+        let rules = self.rules.load();
+        let levels = resolve_levels(&rules, keys);
+
+        let (decision, denied_at) = self.charge_levels(keys, &levels, tokens_requested)?;
+        self.record_levels(keys, &levels, tokens_requested, &decision, denied_at);
+        Ok((decision, denied_at))
+    }
+
+    /// Charge every level in `keys` under its rule in `levels`, outermost first.
+    /// When a level denies the request, the levels already charged are refunded
+    /// and the denying level's index is returned with its decision.
+    fn charge_levels(
+        &self,
+        keys: &[String],
+        levels: &[&Rule],
+        tokens_requested: i32,
+    ) -> Result<(Decision, Option<usize>), Status> {
+        let started = Instant::now();
+        let (tokens, now) = (tokens_requested as u64, self.now());
+        let mut decisions = Vec::with_capacity(keys.len());
+
+        for (level, (key, rule)) in keys.iter().zip(levels).enumerate() {
+            let decision = self.store.charge(key, &rule.limits, tokens, now);
+            if !decision.allowed {
+                for (charged, rule) in keys[..level].iter().zip(levels).rev() {
+                    self.store.refund(charged, &rule.limits, tokens, now);
+                }
+                self.metrics.record_check_latency(started.elapsed());
+                return Ok((decision, Some(level)));
+            }
+            decisions.push(decision);
+        }
+
+        self.metrics.record_check_latency(started.elapsed());
+        Ok((layers::combine_levels(decisions), None))
+    }
+
+    /// Count a final decision: an allowed one under every level's rule, a
+    /// denied one under the rule of the level that denied it
+    fn record_levels(
+        &self,
+        keys: &[String],
+        levels: &[&Rule],
+        tokens_requested: i32,
+        decision: &Decision,
+        denied_at: Option<usize>,
+    ) {
+        let tokens = tokens_requested as u64;
+        match denied_at {
+            Some(level) => {
+                let rule = &levels[level].name;
+                self.metrics.record_decision(rule, false);
+                self.log_decision(&keys[level], rule, tokens, decision);
+            }
+            None => {
+                for rule in levels {
+                    self.metrics.record_decision(&rule.name, true);
+                }
+                if let (Some(leaf), Some(rule)) = (keys.last(), levels.last()) {
+                    self.log_decision(leaf, &rule.name, tokens, decision);
+                }
+            }
+        }
+    }
+
+    fn peek_rate_limit(&self, keys: &[String], tokens_requested: i32) -> (Decision, Option<usize>) {
+        let rules = self.rules.load();
+        self.peek_levels(keys, &resolve_levels(&rules, keys), tokens_requested)
+    }
+
+    fn peek_levels(&self, keys: &[String], levels: &[&Rule], tokens_requested: i32) -> (Decision, Option<usize>) {
+        let now = self.now();
+
+        let decisions: Vec<Decision> = keys
+            .iter()
+            .zip(levels)
+            .map(|(key, rule)| self.store.peek(key, &rule.limits, tokens_requested as u64, now))
+            .collect();
+
+        match decisions.iter().position(|decision| !decision.allowed) {
+            Some(level) => (decisions[level].clone(), Some(level)),
+            None => (layers::combine_levels(decisions), None),
+        }
+    }
+
+    fn refund(&self, id: &str, tokens_requested: i32) {
+        let rules = self.rules.load();
+        let limits = &rules.resolve(id).limits;
+
+        self.store.refund(id, limits, tokens_requested as u64, self.now());
+    }
+
+    /// Remember a charge so it can be refunded until the longest layer would
+    /// have given it back anyway
+    fn reserve(&self, keys: &[String], tokens_requested: i32) -> String {
+        let reservation_id = rand::random::<u64>();
+        let rules = self.rules.load();
+        let now = self.now();
+        // Calendar layers ignore their window; their charges last until the period ends
+        let expires_at = keys
+            .iter()
+            .flat_map(|key| &rules.resolve(key).limits)
+            .map(|limit| match limit.algorithm {
+                Algorithm::Calendar => limit.calendar.next_reset(now),
+                _ => now.saturating_add(limit.window.as_nanos() as u64),
+            })
+            .max()
+            .unwrap_or(now);
+        self.reservations.insert(
+            reservation_id,
+            Reservation {
+                keys: keys.iter().map(|key| key.as_str().into()).collect(),
+                tokens: tokens_requested,
+                expires_at,
+            },
+        );
+
+        format!("{:016x}", reservation_id)
+    }
+
+    fn refund_reservation(&self, reservation_id: &str, tokens: i32) -> Result<i32, Status> {
+        let key = u64::from_str_radix(reservation_id, 16)
+            .map_err(|_| Status::invalid_argument("reservation_id is malformed"))?;
+
+        // Taking the reservation out first means a replayed refund finds nothing
+        let (_, reservation) = self
+            .reservations
+            .remove(&key)
+            .ok_or_else(|| Status::not_found("reservation is unknown or already refunded"))?;
 
-        // Refill tokens if window has elapsed
-        let elapsed = now.duration_since(bucket.last_refill);
-        if elapsed >= self.window_duration {
-            bucket.tokens = self.tokens_per_window;
-            bucket.last_refill = now;
+        if reservation.expires_at <= self.now() {
+            return Err(Status::not_found("reservation has expired"));
         }
 
-        // Check if enough tokens available
-        if bucket.tokens >= tokens_requested {
-            bucket.tokens -= tokens_requested;
-            Ok(true) // Allowed
+        let tokens = if tokens <= 0 || tokens > reservation.tokens {
+            reservation.tokens
         } else {
-            Ok(false) // Rate limited
+            tokens
+        };
+        for key in &reservation.keys {
+            self.refund(key, tokens);
+        }
+
+        Ok(tokens)
+    }
+
+    /// Drop reservations whose window has passed; they can no longer be refunded
+    pub fn purge_expired_reservations(&self) {
+        let now = self.now();
+        self.reservations
+            .retain(|_, reservation| reservation.expires_at > now);
+    }
+
+    fn acquire_lease(&self, id: &str, ttl: Duration, lease_id: u64) -> Decision {
+        let now = self.now();
+
+        let mut leases = match self.leases.get_mut(id) {
+            Some(leases) => leases,
+            None => self.leases.entry(id.into()).or_default(),
+        };
+
+        self.concurrency.try_acquire(&mut leases, lease_id, ttl, now)
+    }
+
+    fn release_lease(&self, id: &str, lease_id: u64) -> bool {
+        let now = self.now();
+
+        let released = match self.leases.get_mut(id) {
+            Some(mut leases) => self.concurrency.release(&mut leases, lease_id, now),
+            None => false,
+        };
+
+        // Keys with nothing in flight need no state
+        self.leases.remove_if(id, |_, leases| leases.is_empty());
+
+        released
+    }
+
+    /// Check every entry in order. With `all_or_nothing`, one denial refunds
+    /// every entry that was charged, so the batch leaves no trace.
+    pub(crate) fn check_rate_limit_batch(
+        &self,
+        entries: &[(Vec<String>, i32)],
+        all_or_nothing: bool,
+    ) -> Result<Vec<(Decision, Option<usize>)>, Status> {
+        let rules = self.rules.load();
+        let levels: Vec<Vec<&Rule>> = entries.iter().map(|(keys, _)| resolve_levels(&rules, keys)).collect();
+
+        // A batch that would be denied anyway is turned away before anything is
+        // charged, so it never holds tokens another caller could be denied for
+        if all_or_nothing {
+            let peeked: Vec<_> = entries
+                .iter()
+                .zip(&levels)
+                .map(|((keys, tokens), levels)| self.peek_levels(keys, levels, *tokens))
+                .collect();
+            if peeked.iter().any(|(decision, _)| !decision.allowed) {
+                self.record_batch(entries, &levels, &peeked, all_or_nothing);
+                return Ok(peeked);
+            }
+        }
+
+        let mut decisions = entries
+            .iter()
+            .zip(&levels)
+            .map(|((keys, tokens), levels)| self.charge_levels(keys, levels, *tokens))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Entries can still be denied once charged, as when they share a key
+        // or another caller got there first
+        if all_or_nothing && decisions.iter().any(|(decision, _)| !decision.allowed) {
+            let now = self.now();
+            for (((keys, tokens), levels), (decision, _)) in entries.iter().zip(&levels).zip(&mut decisions).rev() {
+                if decision.allowed {
+                    for (key, rule) in keys.iter().zip(levels).rev() {
+                        self.store.refund(key, &rule.limits, *tokens as u64, now);
+                    }
+                    decision.remaining = (decision.remaining + *tokens as u64).min(decision.limit);
+                }
+            }
+        }
+
+        self.record_batch(entries, &levels, &decisions, all_or_nothing);
+        Ok(decisions)
+    }
+
+    /// Count a batch's final decisions. Entries aborted because another was
+    /// denied were neither admitted nor denied by their own rule, so they are left out.
+    fn record_batch(
+        &self,
+        entries: &[(Vec<String>, i32)],
+        levels: &[Vec<&Rule>],
+        decisions: &[(Decision, Option<usize>)],
+        all_or_nothing: bool,
+    ) {
+        let aborted = all_or_nothing && decisions.iter().any(|(decision, _)| !decision.allowed);
+        for (((keys, tokens), levels), (decision, denied_at)) in entries.iter().zip(levels).zip(decisions) {
+            if !(aborted && decision.allowed) {
+                self.record_levels(keys, levels, *tokens, decision, *denied_at);
+            }
+        }
+    }
+
+    fn check_stream_entry(&self, req: RateLimitStreamRequest) -> RateLimitStreamResponse {
+        let entry = req.request.unwrap_or_default();
+
+        let checked = self
+            .validate_and_normalize_request(&entry)
+            .and_then(|(keys, tokens)| {
+                let (decision, denied_at) = self.check_levels(&keys, tokens)?;
+                Ok((keys, tokens, decision, denied_at))
+            });
+
+        match checked {
+            Ok((keys, tokens, decision, denied_at)) => {
+                let status = if decision.allowed { "success" } else { "rate_limited" };
+
+                let mut response = rate_limit_response(status, &decision);
+                if let Some(level) = denied_at {
+                    response.limited_by = keys[level].clone();
+                }
+                if decision.allowed && entry.reserve {
+                    response.reservation_id = self.reserve(&keys, tokens);
+                }
+
+                RateLimitStreamResponse {
+                    correlation_id: req.correlation_id,
+                    response: Some(response),
+                    error: String::new(),
+                }
+            }
+            Err(status) => RateLimitStreamResponse {
+                correlation_id: req.correlation_id,
+                response: None,
+                error: status.message().to_string(),
+            },
         }
     }
 }
 
+fn rate_limit_response(status: &str, decision: &Decision) -> RateLimitResponse {
+    RateLimitResponse {
+        status: status.to_string(),
+        limit: decision.limit,
+        remaining: decision.remaining,
+        reset_after: decision.reset_after.try_into().ok(),
+        retry_after: decision.retry_after.try_into().ok(),
+        reservation_id: String::new(),
+        reset_at: SystemTime::now().checked_add(decision.reset_after).map(Into::into),
+        limited_by: String::new(),
+    }
+}
+
+/// The innermost level, which identifies the caller in logs
+/// The rule each level of a key falls under, resolved once per check
+fn resolve_levels<'a>(rules: &'a RuleSet, keys: &[String]) -> Vec<&'a Rule> {
+    keys.iter().map(|key| rules.resolve(key)).collect()
+}
+
+fn leaf(keys: &[String]) -> &str {
+    keys.last().map_or("", String::as_str)
+}
+
+/// Mirror the decision into `x-ratelimit-*` style metadata so proxies can
+/// forward it without decoding the message (denials carry no message at all)
+fn insert_rate_limit_headers(metadata: &mut MetadataMap, decision: &Decision) {
+    let ceil_secs = |duration: Duration| duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+
+    metadata.insert("x-ratelimit-limit", decision.limit.into());
+    metadata.insert("x-ratelimit-remaining", decision.remaining.into());
+    metadata.insert("x-ratelimit-reset", ceil_secs(decision.reset_after).into());
+    if !decision.allowed {
+        metadata.insert("retry-after", ceil_secs(decision.retry_after).into());
+    }
+}
+
+/// RESOURCE_EXHAUSTED carrying standard `google.rpc` RetryInfo and QuotaFailure
+/// details, so any gRPC client can read the delay without parsing the message
+fn rate_limit_exceeded(id: &str, decision: &Decision) -> Status {
+    quota_exceeded(format!("Rate limit exceeded for id: {}", id), id, decision)
+}
+
+fn quota_exceeded(message: String, id: &str, decision: &Decision) -> Status {
+    let retry_info = RetryInfo {
+        retry_delay: decision.retry_after.try_into().ok(),
+    };
+    let quota_failure = QuotaFailure {
+        violations: vec![Violation {
+            subject: format!("id:{}", id),
+            description: format!("Quota of {} tokens exhausted", decision.limit),
+        }],
+    };
+    let details = rpc::Status {
+        code: Code::ResourceExhausted as i32,
+        message: message.clone(),
+        details: vec![
+            Any {
+                type_url: "type.googleapis.com/google.rpc.RetryInfo".to_string(),
+                value: retry_info.encode_to_vec(),
+            },
+            Any {
+                type_url: "type.googleapis.com/google.rpc.QuotaFailure".to_string(),
+                value: quota_failure.encode_to_vec(),
+            },
+        ],
+    };
+
+    let mut metadata = MetadataMap::new();
+    insert_rate_limit_headers(&mut metadata, decision);
+
+    Status::with_details_and_metadata(
+        Code::ResourceExhausted,
+        message,
+        details.encode_to_vec().into(),
+        metadata,
+    )
+}
+
 #[tonic::async_trait]
 impl RateLimiter for RateLimiterService {
     async fn check_rate_limit(
         &self,
         request: Request<RateLimitRequest>,
     ) -> Result<Response<RateLimitResponse>, Status> {
+        let span = telemetry::recording_span(&request);
+        let req = request.into_inner();
+
+        let (keys, tokens) = self.validate_and_normalize_request(&req)?;
+
+        // Check rate limit at every level
+        let (decision, denied_at) = self.check_levels(&keys, tokens)?;
+        if let Some(span) = &span {
+            self.annotate_span(span, &keys, tokens, &decision, denied_at);
+        }
+
+        match denied_at {
+            None => {
+                self.commit().await?;
+
+                let mut reply = rate_limit_response("success", &decision);
+                if req.reserve {
+                    reply.reservation_id = self.reserve(&keys, tokens);
+                }
+
+                let mut response = Response::new(reply);
+                insert_rate_limit_headers(response.metadata_mut(), &decision);
+                Ok(response)
+            }
+            Some(level) => Err(rate_limit_exceeded(&keys[level], &decision)),
+        }
+    }
+
+    async fn peek_rate_limit(
+        &self,
+        request: Request<RateLimitRequest>,
+    ) -> Result<Response<RateLimitResponse>, Status> {
+        let span = telemetry::recording_span(&request);
+        let req = request.into_inner();
+
+        let (keys, tokens) = self.validate_and_normalize_request(&req)?;
+        let (decision, denied_at) = self.peek_rate_limit(&keys, tokens);
+        if let Some(span) = &span {
+            self.annotate_span(span, &keys, tokens, &decision, denied_at);
+        }
+        let status = if decision.allowed { "success" } else { "rate_limited" };
+
+        let mut reply = rate_limit_response(status, &decision);
+        if let Some(level) = denied_at {
+            reply.limited_by = keys[level].clone();
+        }
+
+        let mut response = Response::new(reply);
+        insert_rate_limit_headers(response.metadata_mut(), &decision);
+        Ok(response)
+    }
+
+    async fn check_rate_limit_batch(
+        &self,
+        request: Request<RateLimitBatchRequest>,
+    ) -> Result<Response<RateLimitBatchResponse>, Status> {
+        let span = telemetry::recording_span(&request);
+        let req = request.into_inner();
+
+        let entries = req
+            .entries
+            .iter()
+            .map(|entry| self.validate_and_normalize_request(entry))
+            .collect::<Result<Vec<_>, Status>>()?;
+
+        let decisions = self.check_rate_limit_batch(&entries, req.all_or_nothing)?;
+        let allowed = decisions.iter().all(|(decision, _)| decision.allowed);
+        self.commit().await?;
+
+        // Each entry has its own id, rule and outcome; the span describes the batch
+        if let Some(span) = &span {
+            let tokens: i64 = entries.iter().map(|(_, tokens)| i64::from(*tokens)).sum();
+            span.span().set_attributes([
+                KeyValue::new("ratelimit.entries", entries.len() as i64),
+                KeyValue::new("ratelimit.tokens", tokens),
+                KeyValue::new("ratelimit.decision", if allowed { "allowed" } else { "denied" }),
+            ]);
+        }
+
+        tracing::debug!(entries = entries.len(), all_or_nothing = req.all_or_nothing, allowed, "rate limit batch");
+
+        let results = decisions
+            .iter()
+            .zip(&entries)
+            .zip(&req.entries)
+            .map(|(((decision, denied_at), (keys, tokens)), entry)| {
+                if let Some(level) = denied_at {
+                    let mut result = rate_limit_response("rate_limited", decision);
+                    result.limited_by = keys[*level].clone();
+                    result
+                } else if req.all_or_nothing && !allowed {
+                    rate_limit_response("aborted", decision)
+                } else {
+                    let mut result = rate_limit_response("success", decision);
+                    if entry.reserve {
+                        result.reservation_id = self.reserve(keys, *tokens);
+                    }
+                    result
+                }
+            })
+            .collect();
+
+        Ok(Response::new(RateLimitBatchResponse { results, allowed }))
+    }
+
+    type CheckRateLimitStreamStream =
+        Pin<Box<dyn Stream<Item = Result<RateLimitStreamResponse, Status>> + Send>>;
+
+    async fn check_rate_limit_stream(
+        &self,
+        request: Request<Streaming<RateLimitStreamRequest>>,
+    ) -> Result<Response<Self::CheckRateLimitStreamStream>, Status> {
+        let service = self.clone();
+
+        // Check messages as they arrive rather than one at a time, so allowed ones
+        // share write-ahead log commits; a transport error ends the stream
+        let responses = request.into_inner().map(move |message| {
+            let service = service.clone();
+            async move {
+                let mut response = service.check_stream_entry(message?);
+                if response.response.as_ref().is_some_and(|reply| reply.status == "success") {
+                    if let Err(status) = service.commit().await {
+                        response.response = None;
+                        response.error = status.message().to_string();
+                    }
+                }
+                Ok(response)
+            }
+        });
+        let responses = futures_util::StreamExt::buffer_unordered(responses, STREAM_CONCURRENCY);
+
+        Ok(Response::new(Box::pin(responses)))
+    }
+
+    async fn refund_rate_limit(
+        &self,
+        request: Request<RefundRequest>,
+    ) -> Result<Response<RefundResponse>, Status> {
+        let req = request.into_inner();
+
+        let tokens = self.refund_reservation(&req.reservation_id, req.tokens)?;
+        tracing::debug!(reservation = %req.reservation_id, tokens, "rate limit refunded");
+
+        Ok(Response::new(RefundResponse {
+            tokens_refunded: tokens as u64,
+        }))
+    }
+
+    async fn acquire_lease(
+        &self,
+        request: Request<AcquireLeaseRequest>,
+    ) -> Result<Response<AcquireLeaseResponse>, Status> {
+        let span = telemetry::recording_span(&request);
         let req = request.into_inner();
 
-        let tokens = self.validate_and_normalize_request(&req)?;
+        if req.id.is_empty() {
+            return Err(Status::invalid_argument("id is required"));
+        }
 
-        // Check rate limit
-        let allowed = self.check_rate_limit(&req.id, tokens)?;
+        let requested_ttl = req
+            .ttl
+            .and_then(|ttl| Duration::try_from(ttl).ok())
+            .unwrap_or_default();
+        let ttl = self.concurrency.ttl_for(requested_ttl);
+        let lease_id = rand::random::<u64>();
 
-        if allowed {
-            tracing::info!("Rate limit ALLOWED - id: {}, tokens: {}", req.id, tokens);
+        let decision = self.acquire_lease(&req.id, ttl, lease_id);
+        if let Some(span) = &span {
+            span.span().set_attributes([
+                KeyValue::new("ratelimit.id", req.id.clone()),
+                KeyValue::new("ratelimit.decision", if decision.allowed { "allowed" } else { "denied" }),
+            ]);
+        }
 
-            let reply = RateLimitResponse {
-                status: "success".to_string(),
-            };
+        if decision.allowed {
+            tracing::debug!(id = %req.id, in_flight = decision.limit - decision.remaining, "lease acquired");
 
-            Ok(Response::new(reply))
+            Ok(Response::new(AcquireLeaseResponse {
+                lease_id: format!("{:016x}", lease_id),
+                limit: decision.limit,
+                remaining: decision.remaining,
+                expires_after: ttl.try_into().ok(),
+            }))
         } else {
-            tracing::warn!("Rate limit EXCEEDED - id: {}, tokens: {}", req.id, tokens);
+            tracing::debug!(id = %req.id, in_flight = decision.limit, "lease rejected");
 
-            Err(Status::resource_exhausted(format!(
-                "Rate limit exceeded for id: {}",
-                req.id
-            )))
+            Err(quota_exceeded(
+                format!("Concurrency limit exceeded for id: {}", req.id),
+                &req.id,
+                &decision,
+            ))
         }
     }
 
+    async fn release_lease(
+        &self,
+        request: Request<ReleaseLeaseRequest>,
+    ) -> Result<Response<ReleaseLeaseResponse>, Status> {
+        let req = request.into_inner();
+
+        let lease_id = u64::from_str_radix(&req.lease_id, 16)
+            .map_err(|_| Status::invalid_argument("lease_id is malformed"))?;
+
+        let released = self.release_lease(&req.id, lease_id);
+        tracing::debug!(id = %req.id, released, "lease released");
+
+        Ok(Response::new(ReleaseLeaseResponse { released }))
+    }
+
     async fn heart_beat(
         &self,
-        request: Request<HeartBeatRequest>,
+        _request: Request<HeartBeatRequest>,
     ) -> Result<Response<HeartBeatResponse>, Status> {
         Ok(Response::new(HeartBeatResponse {}))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rust_rate_limiter::clock::ManualClock;
+
+    use super::*;
+    use crate::rate_limiter::Dimension;
+
+    fn per_minute(limit: u64) -> Limit {
+        Limit {
+            limit,
+            burst: limit,
+            ..Limit::default()
+        }
+    }
+
+    /// A service with an in-memory store, driven by a clock the test moves
+    fn service(rules: RuleSet) -> (RateLimiterService, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        clock.set(Duration::from_secs(1_000));
+        let service = RateLimiterService::new(
+            rules,
+            ConcurrencyLimit::default(),
+            Arc::new(MemoryStore::default()),
+            clock.clone(),
+        );
+        (service, clock)
+    }
+
+    fn entry(id: &str, tokens: i32) -> (Vec<String>, i32) {
+        (vec![id.to_string()], tokens)
+    }
+
+    fn remaining(service: &RateLimiterService, id: &str) -> u64 {
+        service.peek_rate_limit(&[id.to_string()], 0).0.remaining
+    }
+
+    #[test]
+    fn an_all_or_nothing_batch_refunds_every_entry_when_one_is_denied() {
+        let (service, _) = service(RuleSet::single(per_minute(5)));
+        let entries = [entry("a", 3), entry("b", 3), entry("a", 3)];
+
+        let decisions = service.check_rate_limit_batch(&entries, true).unwrap();
+        let allowed: Vec<bool> = decisions.iter().map(|(decision, _)| decision.allowed).collect();
+        assert_eq!(allowed, [true, true, false]);
+        // The entries that went through report what is left after the refund
+        assert_eq!(decisions[0].0.remaining, 5);
+        assert_eq!(remaining(&service, "a"), 5);
+        assert_eq!(remaining(&service, "b"), 5);
+
+        // Only the denial is counted; the refunded entries were never admitted
+        let metrics = service.metrics.render(service.store.as_ref());
+        assert!(metrics.contains("rate_limiter_decisions_total{rule=\"default\",decision=\"allowed\"} 0"));
+        assert!(metrics.contains("rate_limiter_decisions_total{rule=\"default\",decision=\"denied\"} 1"));
+    }
+
+    #[test]
+    fn a_batch_that_would_be_denied_charges_and_counts_nothing_it_aborts() {
+        let (service, _) = service(RuleSet::single(per_minute(5)));
+        let entries = [entry("a", 3), entry("b", 6)];
+
+        let decisions = service.check_rate_limit_batch(&entries, true).unwrap();
+        let allowed: Vec<bool> = decisions.iter().map(|(decision, _)| decision.allowed).collect();
+        assert_eq!(allowed, [true, false]);
+        assert_eq!(decisions[0].0.remaining, 5);
+        assert_eq!(remaining(&service, "a"), 5);
+
+        let metrics = service.metrics.render(service.store.as_ref());
+        assert!(metrics.contains("rate_limiter_decisions_total{rule=\"default\",decision=\"allowed\"} 0"));
+        assert!(metrics.contains("rate_limiter_decisions_total{rule=\"default\",decision=\"denied\"} 1"));
+    }
+
+    #[test]
+    fn a_batch_without_all_or_nothing_keeps_what_was_allowed() {
+        let (service, _) = service(RuleSet::single(per_minute(5)));
+        let entries = [entry("a", 3), entry("b", 3), entry("a", 3)];
+
+        service.check_rate_limit_batch(&entries, false).unwrap();
+        assert_eq!(remaining(&service, "a"), 2);
+        assert_eq!(remaining(&service, "b"), 2);
+    }
+
+    fn request(id: &str, tokens: i32) -> RateLimitRequest {
+        RateLimitRequest {
+            id: id.to_string(),
+            tokens_requested: tokens,
+            ..RateLimitRequest::default()
+        }
+    }
+
+    async fn reserve(service: &RateLimiterService, id: &str, tokens: i32) -> String {
+        let req = RateLimitRequest {
+            reserve: true,
+            ..request(id, tokens)
+        };
+        let response = RateLimiter::check_rate_limit(service, Request::new(req)).await.unwrap();
+        response.into_inner().reservation_id
+    }
+
+    async fn refund(service: &RateLimiterService, reservation_id: &str, tokens: i32) -> Result<u64, Code> {
+        let req = RefundRequest {
+            reservation_id: reservation_id.to_string(),
+            tokens,
+        };
+        match service.refund_rate_limit(Request::new(req)).await {
+            Ok(response) => Ok(response.into_inner().tokens_refunded),
+            Err(status) => Err(status.code()),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_reservation_is_refunded_once() {
+        let (service, _) = service(RuleSet::single(per_minute(5)));
+        let reservation = reserve(&service, "a", 4).await;
+        assert_eq!(remaining(&service, "a"), 1);
+
+        assert_eq!(refund(&service, &reservation, 0).await, Ok(4));
+        assert_eq!(remaining(&service, "a"), 5);
+        assert_eq!(refund(&service, &reservation, 0).await, Err(Code::NotFound));
+        assert_eq!(remaining(&service, "a"), 5);
+    }
+
+    #[tokio::test]
+    async fn a_partial_refund_never_returns_more_than_was_reserved() {
+        let (service, _) = service(RuleSet::single(per_minute(5)));
+        let small = reserve(&service, "a", 2).await;
+        reserve(&service, "a", 3).await;
+
+        assert_eq!(refund(&service, &small, 3).await, Ok(2));
+        assert_eq!(remaining(&service, "a"), 2);
+        assert_eq!(refund(&service, "not-hex", 0).await, Err(Code::InvalidArgument));
+    }
+
+    #[tokio::test]
+    async fn a_reservation_expires_with_the_window() {
+        let (service, clock) = service(RuleSet::single(per_minute(5)));
+        let reservation = reserve(&service, "a", 4).await;
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(refund(&service, &reservation, 0).await, Err(Code::NotFound));
+        service.purge_expired_reservations();
+        assert!(service.reservations.is_empty());
+    }
+
+    #[test]
+    fn released_and_expired_leases_leave_no_state_behind() {
+        let (service, clock) = service(RuleSet::single(per_minute(5)));
+        let ttl = Duration::from_secs(10);
+
+        assert!(service.acquire_lease("a", ttl, 1).allowed);
+        assert!(service.release_lease("a", 1));
+        assert!(!service.release_lease("a", 1));
+        assert!(service.leases.is_empty());
+
+        service.acquire_lease("a", ttl, 2);
+        clock.advance(ttl);
+        service.sweep();
+        assert!(service.leases.is_empty());
+    }
+
+    fn dimensions(dimensions: &[(&str, &str)]) -> RateLimitRequest {
+        RateLimitRequest {
+            dimensions: dimensions
+                .iter()
+                .map(|(name, value)| Dimension {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            ..RateLimitRequest::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn a_denial_names_the_level_that_denied_it() {
+        let rules = RuleSet::from_toml(
+            r#"
+            [default]
+            limit = 10
+
+            [[rules]]
+            level = "org"
+            limit = 2
+            "#,
+            &Limit::default(),
+        )
+        .unwrap();
+        let (service, _) = service(rules);
+        let alice = dimensions(&[("org", "acme"), ("user", "alice")]);
+        let bob = dimensions(&[("org", "acme"), ("user", "bob")]);
+
+        assert!(service.check(&alice).await.unwrap().allowed);
+        assert!(service.check(&bob).await.unwrap().allowed);
+        let denied = RateLimiter::peek_rate_limit(&service, Request::new(alice.clone())).await.unwrap();
+        assert_eq!(denied.into_inner().limited_by, "org=acme");
+
+        // The user level charged before the org denied it gets its token back
+        assert!(!service.check(&alice).await.unwrap().allowed);
+        assert_eq!(remaining(&service, "org=acme/user=alice"), 9);
+    }
+
+    #[tokio::test]
+    async fn dimensions_need_names_and_values() {
+        let (service, _) = service(RuleSet::single(per_minute(5)));
+        for req in [
+            dimensions(&[("org", "")]),
+            dimensions(&[("", "acme")]),
+            RateLimitRequest {
+                id: "a".to_string(),
+                ..dimensions(&[("org", "acme")])
+            },
+        ] {
+            assert_eq!(service.check(&req).await.unwrap_err().code(), Code::InvalidArgument);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_charge_racing_a_reload_keeps_what_the_key_spent() {
+        let (service, _) = service(RuleSet::single(per_minute(10)));
+        assert!(service.check(&request("a", 10)).await.unwrap().allowed);
+
+        // The new rules are in effect before the rescale pass reaches the key
+        let gcra = Limit {
+            algorithm: Algorithm::Gcra,
+            ..per_minute(10)
+        };
+        let previous = service.rules.swap(Arc::new(RuleSet::single(gcra)));
+        assert!(!service.check(&request("a", 1)).await.unwrap().allowed);
+
+        service.store.rescale(&previous, &service.rules(), service.now());
+        assert!(!service.check(&request("a", 1)).await.unwrap().allowed);
+        assert_eq!(remaining(&service, "a"), 0);
+    }
+
+    #[tokio::test]
+    async fn flat_ids_cannot_reach_a_level_key() {
+        let (service, _) = service(RuleSet::single(per_minute(5)));
+        for id in ["org=acme", "org=acme/user=alice", "domain=edge/remote_address=10.0.0.1"] {
+            assert_eq!(service.check(&request(id, 1)).await.unwrap_err().code(), Code::InvalidArgument);
+        }
+        assert!(service.check(&request("api/v1/search", 1)).await.unwrap().allowed);
+    }
+}
@@ -0,0 +1,407 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+
+use rust_rate_limiter::algorithm::{Algorithm, Decision, Limit};
+
+use crate::rate_limiter::RateLimitRequest;
+use crate::rate_limiter_service::RateLimiterService;
+
+// Anything larger is not a rate limit command
+const MAX_ARGS: usize = 64;
+const MAX_ARG_LEN: usize = 64 * 1024;
+// Unknown command names are quoted back in the error only up to this length
+const MAX_ECHOED_NAME: usize = 128;
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A reply, encoded for whichever protocol version the connection negotiated
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Array(Vec<Reply>),
+    // RESP3 only; sent to RESP2 clients as a flat array of keys and values
+    Map(Vec<(&'static str, Reply)>),
+}
+
+impl Reply {
+    fn encode(&self, resp3: bool, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Reply::Error(message) => {
+                // A line break would end the error early and desync the client
+                let message: String = message.chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+                out.extend_from_slice(format!("-{}\r\n", message).as_bytes())
+            }
+            Reply::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(s) => out.extend_from_slice(format!("${}\r\n{}\r\n", s.len(), s).as_bytes()),
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(resp3, out);
+                }
+            }
+            Reply::Map(entries) => {
+                if resp3 {
+                    out.extend_from_slice(format!("%{}\r\n", entries.len()).as_bytes());
+                } else {
+                    out.extend_from_slice(format!("*{}\r\n", entries.len() * 2).as_bytes());
+                }
+                for (key, value) in entries {
+                    Reply::Bulk(key.to_string()).encode(resp3, out);
+                    value.encode(resp3, out);
+                }
+            }
+        }
+    }
+}
+
+/// Accept Redis protocol clients on `addr` until `shutdown` resolves, then
+/// close their connections
+pub async fn serve(
+    service: RateLimiterService,
+    addr: SocketAddr,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("🧱 Redis protocol listener on {}", addr);
+
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                stream.set_nodelay(true)?;
+                connections.spawn(handle_connection(service.clone(), stream));
+            }
+            // Reap finished connections so the set does not grow
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut shutdown => break,
+        }
+    }
+
+    connections.shutdown().await;
+    Ok(())
+}
+
+async fn handle_connection(service: RateLimiterService, stream: TcpStream) {
+    let client_id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut resp3 = false;
+    let mut out = Vec::new();
+
+    loop {
+        let args = match read_command(&mut reader).await {
+            Ok(Some(args)) => args,
+            Ok(None) => return,
+            Err(err) => {
+                // The stream can no longer be parsed; say why and hang up
+                let _ = writer.write_all(format!("-ERR Protocol error: {}\r\n", err).as_bytes()).await;
+                let _ = writer.flush().await;
+                return;
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+
+        let command = args[0].to_ascii_uppercase();
+        let reply = match command.as_str() {
+            "QUIT" => {
+                let _ = writer.write_all(b"+OK\r\n").await;
+                let _ = writer.flush().await;
+                return;
+            }
+            "HELLO" => hello(&args, client_id, &mut resp3),
+            _ => execute(&service, &command, &args).await,
+        };
+
+        out.clear();
+        reply.encode(resp3, &mut out);
+        if writer.write_all(&out).await.is_err() {
+            return;
+        }
+        // Pipelined commands are answered together once the batch is read
+        if reader.buffer().is_empty() && writer.flush().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// The next command as its arguments, or None once the client has hung up.
+/// Accepts both RESP arrays of bulk strings and inline commands.
+async fn read_command<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> std::io::Result<Option<Vec<String>>> {
+    let line = match read_line(reader).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let count = match line.strip_prefix('*') {
+        Some(count) => parse_len(count, MAX_ARGS)?,
+        None => return Ok(Some(line.split_whitespace().map(str::to_string).collect())),
+    };
+
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let header = read_line(reader).await?.ok_or_else(|| protocol_error("unexpected end of stream"))?;
+        let len = header
+            .strip_prefix('$')
+            .ok_or_else(|| protocol_error("expected '$'"))
+            .and_then(|len| parse_len(len, MAX_ARG_LEN))?;
+
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not terminated by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(String::from_utf8(arg).map_err(|_| protocol_error("arguments must be UTF-8"))?);
+    }
+
+    Ok(Some(args))
+}
+
+async fn read_line<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> std::io::Result<Option<String>> {
+    let mut line = String::new();
+    // Bounded so a client cannot make us buffer an endless line
+    if reader.take(MAX_ARG_LEN as u64).read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(protocol_error("line too long"));
+    }
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+fn parse_len(len: &str, max: usize) -> std::io::Result<usize> {
+    match len.parse::<usize>() {
+        Ok(len) if len <= max => Ok(len),
+        _ => Err(protocol_error("invalid length")),
+    }
+}
+
+fn protocol_error(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// `HELLO [protover ...]`: switch protocol versions and describe the server
+fn hello(args: &[String], client_id: u64, resp3: &mut bool) -> Reply {
+    match args.get(1).map(|version| version.as_str()) {
+        None => {}
+        Some("2") => *resp3 = false,
+        Some("3") => *resp3 = true,
+        Some(_) => return Reply::Error("NOPROTO unsupported protocol version".to_string()),
+    }
+
+    Reply::Map(vec![
+        ("server", Reply::Bulk(env!("CARGO_PKG_NAME").to_string())),
+        ("version", Reply::Bulk(env!("CARGO_PKG_VERSION").to_string())),
+        ("proto", Reply::Integer(if *resp3 { 3 } else { 2 })),
+        ("id", Reply::Integer(client_id as i64)),
+        ("mode", Reply::Bulk("standalone".to_string())),
+        ("role", Reply::Bulk("master".to_string())),
+        ("modules", Reply::Array(Vec::new())),
+    ])
+}
+
+async fn execute(service: &RateLimiterService, command: &str, args: &[String]) -> Reply {
+    match command {
+        "PING" => match args.get(1) {
+            Some(message) => Reply::Bulk(message.clone()),
+            None => Reply::Simple("PONG"),
+        },
+        "ECHO" if args.len() == 2 => Reply::Bulk(args[1].clone()),
+        // Sent by client libraries on connect; there is nothing to set up
+        "CLIENT" | "SELECT" => Reply::Simple("OK"),
+        "COMMAND" => Reply::Array(Vec::new()),
+        "CL.THROTTLE" => throttle(service, args).await,
+        "RL.CHECK" => check(service, args).await,
+        "ECHO" => wrong_arity(command),
+        _ => {
+            let name: String = args[0].chars().take(MAX_ECHOED_NAME).collect();
+            Reply::Error(format!("ERR unknown command '{}'", name))
+        }
+    }
+}
+
+/// `CL.THROTTLE key max_burst count period [quantity]`, as in redis-cell: a
+/// GCRA limit of `count` per `period` seconds with room for `max_burst` more,
+/// sent with every call rather than taken from the rules
+async fn throttle(service: &RateLimiterService, args: &[String]) -> Reply {
+    if !(5..=6).contains(&args.len()) {
+        return wrong_arity("cl.throttle");
+    }
+
+    let number = |arg: Option<&String>, default: u64| match arg {
+        Some(arg) => arg.parse::<u64>().ok(),
+        None => Some(default),
+    };
+    let (Some(max_burst), Some(count), Some(period), Some(quantity)) = (
+        number(args.get(2), 0),
+        number(args.get(3), 0),
+        number(args.get(4), 0),
+        number(args.get(5), 1),
+    ) else {
+        return Reply::Error("ERR value is not an integer or out of range".to_string());
+    };
+    if count == 0 || period == 0 {
+        return Reply::Error("ERR count and period must be positive".to_string());
+    }
+
+    let limit = Limit {
+        algorithm: Algorithm::Gcra,
+        limit: count,
+        window: Duration::from_secs(period),
+        burst: max_burst.saturating_add(1),
+        ..Limit::default()
+    };
+
+    match service.throttle(&args[1], &limit, quantity).await {
+        Ok(decision) => decision_reply(&decision),
+        Err(status) => Reply::Error(format!("ERR {}", status.message())),
+    }
+}
+
+/// `RL.CHECK key [tokens]`: charge `key` under the server's rules. Replies in
+/// the same shape as CL.THROTTLE.
+async fn check(service: &RateLimiterService, args: &[String]) -> Reply {
+    if !(2..=3).contains(&args.len()) {
+        return wrong_arity("rl.check");
+    }
+
+    let tokens_requested = match args.get(2).map(|tokens| tokens.parse::<i32>()) {
+        Some(Ok(tokens)) => tokens,
+        Some(Err(_)) => return Reply::Error("ERR value is not an integer or out of range".to_string()),
+        None => 1,
+    };
+    let request = RateLimitRequest {
+        id: args[1].clone(),
+        tokens_requested,
+        ..Default::default()
+    };
+
+    match service.check(&request).await {
+        Ok(decision) => decision_reply(&decision),
+        Err(status) => Reply::Error(format!("ERR {}", status.message())),
+    }
+}
+
+/// redis-cell's reply: limited (0 or 1), limit, remaining, seconds until a
+/// retry can succeed (-1 when allowed) and seconds until the key is full again
+fn decision_reply(decision: &Decision) -> Reply {
+    let ceil_secs = |duration: Duration| (duration.as_secs() + u64::from(duration.subsec_nanos() > 0)) as i64;
+
+    Reply::Array(vec![
+        Reply::Integer(i64::from(!decision.allowed)),
+        Reply::Integer(decision.limit as i64),
+        Reply::Integer(decision.remaining as i64),
+        Reply::Integer(if decision.allowed { -1 } else { ceil_secs(decision.retry_after) }),
+        Reply::Integer(ceil_secs(decision.reset_after)),
+    ])
+}
+
+fn wrong_arity(command: &str) -> Reply {
+    Reply::Error(format!("ERR wrong number of arguments for '{}' command", command.to_ascii_lowercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_all(mut input: &[u8]) -> Vec<std::io::Result<Option<Vec<String>>>> {
+        let mut commands = Vec::new();
+        loop {
+            let command = read_command(&mut input).await;
+            let done = !matches!(command, Ok(Some(_)));
+            commands.push(command);
+            if done {
+                return commands;
+            }
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn reads_arrays_of_bulk_strings() {
+        let mut input: &[u8] = b"*3\r\n$11\r\nCL.THROTTLE\r\n$4\r\nuser\r\n$2\r\n15\r\n";
+        assert_eq!(read_command(&mut input).await.unwrap(), Some(args(&["CL.THROTTLE", "user", "15"])));
+        assert_eq!(read_command(&mut input).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reads_pipelined_and_inline_commands() {
+        let commands = read_all(b"PING\r\n*1\r\n$4\r\nPING\r\nHELLO  3\n*0\r\n").await;
+        let commands: Vec<_> = commands.into_iter().map(Result::unwrap).collect();
+        assert_eq!(
+            commands,
+            vec![
+                Some(args(&["PING"])),
+                Some(args(&["PING"])),
+                Some(args(&["HELLO", "3"])),
+                Some(Vec::new()),
+                None,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn bulk_strings_may_hold_line_breaks() {
+        let mut input: &[u8] = b"*1\r\n$4\r\na\r\nb\r\n";
+        assert_eq!(read_command(&mut input).await.unwrap(), Some(args(&["a\r\nb"])));
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_commands() {
+        for input in [
+            &b"*2\r\n$4\r\nPING\r\n"[..],
+            b"*1\r\n+PING\r\n",
+            b"*1\r\n$4\r\nPINGPONG\r\n",
+            b"*1\r\n$-1\r\n",
+            b"*65\r\n",
+            b"*1\r\n$2\r\n\xff\xfe\r\n",
+        ] {
+            let mut reader = input;
+            let err = read_command(&mut reader).await;
+            assert!(err.is_err(), "{:?} gave {:?}", String::from_utf8_lossy(input), err);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_lines_longer_than_an_argument() {
+        let mut input = vec![b'a'; MAX_ARG_LEN + 1];
+        input.extend_from_slice(b"\r\n");
+        let err = read_command(&mut &input[..]).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn errors_never_break_the_line() {
+        let mut out = Vec::new();
+        Reply::Error("ERR unknown command 'a\r\nb'".to_string()).encode(false, &mut out);
+        assert_eq!(out, b"-ERR unknown command 'a  b'\r\n");
+    }
+
+    #[test]
+    fn maps_are_flattened_for_resp2() {
+        let reply = Reply::Map(vec![("limit", Reply::Integer(10))]);
+        let mut out = Vec::new();
+        reply.encode(false, &mut out);
+        assert_eq!(out, b"*2\r\n$5\r\nlimit\r\n:10\r\n");
+
+        out.clear();
+        reply.encode(true, &mut out);
+        assert_eq!(out, b"%1\r\n$5\r\nlimit\r\n:10\r\n");
+    }
+}
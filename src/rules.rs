@@ -0,0 +1,361 @@
+/// Per-id limit rules, loaded from a TOML file
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+use crate::algorithm::{Algorithm, Limit};
+use crate::calendar::{Calendar, Period};
+use crate::hierarchy;
+
+/// Named limits, chosen for an id by the rule set. A request must fit every
+/// layer in `limits`; most rules have just one.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub name: String,
+    pub limits: Vec<Limit>,
+}
+
+/// The default rule plus overrides matched by exact id, prefix, glob or hierarchy level
+#[derive(Clone, Debug)]
+pub struct RuleSet {
+    default: Rule,
+    exact: HashMap<String, Rule>,
+    // Longest prefix first
+    prefixes: Vec<(String, Rule)>,
+    // Most literal characters first
+    globs: Vec<(String, Rule)>,
+    // Keyed by the dimension a hierarchical key ends in
+    levels: HashMap<String, Rule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    default: Option<LimitSpec>,
+    #[serde(default)]
+    rules: Vec<RuleSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    name: Option<String>,
+    id: Option<String>,
+    prefix: Option<String>,
+    glob: Option<String>,
+    level: Option<String>,
+    algorithm: Option<Algorithm>,
+    limit: Option<u64>,
+    window_secs: Option<u64>,
+    burst: Option<u64>,
+    period: Option<Period>,
+    timezone: Option<Tz>,
+    // Instead of the limit fields above: several limits, all enforced
+    layers: Option<Vec<LimitSpec>>,
+}
+
+/// Limit fields as written in the file; anything left out comes from the default
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LimitSpec {
+    algorithm: Option<Algorithm>,
+    limit: Option<u64>,
+    window_secs: Option<u64>,
+    burst: Option<u64>,
+    period: Option<Period>,
+    timezone: Option<Tz>,
+}
+
+impl LimitSpec {
+    fn is_empty(&self) -> bool {
+        self.algorithm.is_none()
+            && self.limit.is_none()
+            && self.window_secs.is_none()
+            && self.burst.is_none()
+            && self.period.is_none()
+            && self.timezone.is_none()
+    }
+
+    fn resolve(&self, base: &Limit) -> Limit {
+        let limit = self.limit.unwrap_or(base.limit);
+
+        Limit {
+            algorithm: self.algorithm.unwrap_or(base.algorithm),
+            limit,
+            window: self.window_secs.map_or(base.window, Duration::from_secs),
+            // A rule that only raises the rate should also raise the burst
+            burst: self.burst.unwrap_or(if self.limit.is_some() { limit } else { base.burst }),
+            calendar: Calendar {
+                period: self.period.unwrap_or(base.calendar.period),
+                timezone: self.timezone.unwrap_or(base.calendar.timezone),
+            },
+        }
+    }
+}
+
+impl RuleSet {
+    /// Every id gets `limit`
+    pub fn single(limit: Limit) -> Self {
+        Self {
+            default: Rule {
+                name: "default".to_string(),
+                limits: vec![limit],
+            },
+            exact: HashMap::new(),
+            prefixes: Vec::new(),
+            globs: Vec::new(),
+            levels: HashMap::new(),
+        }
+    }
+
+    /// Load rules from a TOML file; fields missing from `[default]` come from `fallback`
+    pub fn load(path: impl AsRef<Path>, fallback: &Limit) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("failed to read rules file {}: {}", path.display(), err))?;
+
+        Self::from_toml(&contents, fallback)
+            .map_err(|err| format!("invalid rules file {}: {}", path.display(), err).into())
+    }
+
+    pub fn from_toml(contents: &str, fallback: &Limit) -> Result<Self, Box<dyn std::error::Error>> {
+        let file: RulesFile = toml::from_str(contents)?;
+
+        let mut rule_set = Self::single(file.default.unwrap_or_default().resolve(fallback));
+        validate(&rule_set.default)?;
+
+        for (index, spec) in file.rules.into_iter().enumerate() {
+            let name = spec.name.unwrap_or_else(|| format!("rule-{}", index + 1));
+            let limit = LimitSpec {
+                algorithm: spec.algorithm,
+                limit: spec.limit,
+                window_secs: spec.window_secs,
+                burst: spec.burst,
+                period: spec.period,
+                timezone: spec.timezone,
+            };
+
+            let base = &rule_set.default.limits[0];
+            let limits = match spec.layers {
+                None => vec![limit.resolve(base)],
+                Some(layers) if limit.is_empty() => layers.iter().map(|layer| layer.resolve(base)).collect(),
+                Some(_) => return Err(format!("rule {} sets both layers and limit fields", name).into()),
+            };
+            let rule = Rule { name, limits };
+            validate(&rule)?;
+
+            match (spec.id, spec.prefix, spec.glob, spec.level) {
+                (Some(id), None, None, None) => {
+                    rule_set.exact.insert(id, rule);
+                }
+                (None, Some(prefix), None, None) => rule_set.prefixes.push((prefix, rule)),
+                (None, None, Some(glob), None) => rule_set.globs.push((glob, rule)),
+                (None, None, None, Some(level)) => {
+                    rule_set.levels.insert(level, rule);
+                }
+                _ => {
+                    return Err(format!(
+                        "rule {} must set exactly one of id, prefix, glob or level",
+                        rule.name
+                    )
+                    .into())
+                }
+            }
+        }
+
+        rule_set
+            .prefixes
+            .sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        rule_set
+            .globs
+            .sort_by_key(|(glob, _)| std::cmp::Reverse(glob.chars().filter(|&c| c != '*' && c != '?').count()));
+
+        Ok(rule_set)
+    }
+
+    /// The most specific rule for `id`: exact, then longest prefix, then glob,
+    /// then the level a hierarchical key ends in, then default
+    pub fn resolve(&self, id: &str) -> &Rule {
+        if let Some(rule) = self.exact.get(id) {
+            return rule;
+        }
+
+        self.prefixes
+            .iter()
+            .find(|(prefix, _)| id.starts_with(prefix.as_str()))
+            .or_else(|| self.globs.iter().find(|(glob, _)| glob_match(glob, id)))
+            .map(|(_, rule)| rule)
+            .or_else(|| hierarchy::level_name(id).and_then(|level| self.levels.get(level)))
+            .unwrap_or(&self.default)
+    }
+
+    /// Number of rules, counting the default
+    pub fn rule_count(&self) -> usize {
+        1 + self.exact.len() + self.prefixes.len() + self.globs.len() + self.levels.len()
+    }
+}
+
+fn validate(rule: &Rule) -> Result<(), Box<dyn std::error::Error>> {
+    if rule.limits.is_empty() {
+        return Err(format!("rule {} needs at least one layer", rule.name).into());
+    }
+    if rule.limits.iter().any(|limit| limit.limit == 0 || limit.window.is_zero()) {
+        return Err(format!("rule {} needs a non-zero limit and window", rule.name).into());
+    }
+    Ok(())
+}
+
+/// `*` matches any run of bytes, `?` exactly one
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+
+    let (mut p, mut t) = (0, 0);
+    // Where to resume if the current attempt fails: after the last `*`, and the text it stopped at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p + 1, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` swallow one more character
+                Some((resume_p, resume_t)) => {
+                    p = resume_p;
+                    t = resume_t + 1;
+                    backtrack = Some((resume_p, resume_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule_name<'a>(rules: &'a RuleSet, id: &str) -> &'a str {
+        &rules.resolve(id).name
+    }
+
+    #[test]
+    fn glob_matches_stars_and_question_marks() {
+        assert!(glob_match("user:*", "user:"));
+        assert!(glob_match("user:*", "user:42"));
+        assert!(glob_match("*:admin", "user:admin"));
+        assert!(glob_match("a*b*c", "a-b-b-c"));
+        assert!(glob_match("user:??", "user:42"));
+        assert!(glob_match("*", ""));
+
+        assert!(!glob_match("user:??", "user:4"));
+        assert!(!glob_match("user:*", "users:42"));
+        assert!(!glob_match("a*b*c", "a-b-b-d"));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn resolve_prefers_the_most_specific_rule() {
+        let rules = RuleSet::from_toml(
+            r#"
+            [default]
+            limit = 10
+
+            [[rules]]
+            name = "exact"
+            id = "api:users:42"
+            limit = 1
+
+            [[rules]]
+            name = "short-prefix"
+            prefix = "api:"
+            limit = 2
+
+            [[rules]]
+            name = "long-prefix"
+            prefix = "api:users:"
+            limit = 3
+
+            [[rules]]
+            name = "loose-glob"
+            glob = "*:admin"
+            limit = 4
+
+            [[rules]]
+            name = "tight-glob"
+            glob = "web:*:admin"
+            limit = 5
+
+            [[rules]]
+            name = "user-level"
+            level = "user"
+            limit = 6
+            "#,
+            &Limit::default(),
+        )
+        .unwrap();
+
+        assert_eq!(rule_name(&rules, "api:users:42"), "exact");
+        assert_eq!(rule_name(&rules, "api:users:7"), "long-prefix");
+        assert_eq!(rule_name(&rules, "api:orders:7"), "short-prefix");
+        // A prefix beats a glob that also matches
+        assert_eq!(rule_name(&rules, "api:x:admin"), "short-prefix");
+        assert_eq!(rule_name(&rules, "web:eu:admin"), "tight-glob");
+        assert_eq!(rule_name(&rules, "cli:admin"), "loose-glob");
+        assert_eq!(rule_name(&rules, "org=acme/user=alice"), "user-level");
+        assert_eq!(rule_name(&rules, "org=acme"), "default");
+        assert_eq!(rule_name(&rules, "other"), "default");
+    }
+
+    #[test]
+    fn rules_inherit_unset_fields_from_the_default() {
+        let rules = RuleSet::from_toml(
+            r#"
+            [default]
+            algorithm = "gcra"
+            limit = 10
+            window_secs = 30
+            burst = 20
+
+            [[rules]]
+            id = "faster"
+            limit = 50
+            "#,
+            &Limit::default(),
+        )
+        .unwrap();
+
+        let limit = &rules.resolve("faster").limits[0];
+        assert_eq!(limit.algorithm, Algorithm::Gcra);
+        assert_eq!(limit.window, Duration::from_secs(30));
+        // Raising the rate raises the burst with it
+        assert_eq!(limit.burst, 50);
+        assert_eq!(rules.resolve("faster").name, "rule-1");
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        let fallback = Limit::default();
+        for contents in [
+            "[[rules]]\nlimit = 5\n",
+            "[[rules]]\nid = \"a\"\nprefix = \"b\"\n",
+            "[[rules]]\nid = \"a\"\nlimit = 0\n",
+            "[[rules]]\nid = \"a\"\nlimit = 5\nlayers = [{ limit = 1 }]\n",
+            "[[rules]]\nid = \"a\"\nlayers = []\n",
+            "[[rules]]\nid = \"a\"\nunknown = 1\n",
+        ] {
+            assert!(RuleSet::from_toml(contents, &fallback).is_err(), "{}", contents);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use tokio::signal::unix::{signal, SignalKind};
+
+use rust_rate_limiter::algorithm::Limit;
+use rust_rate_limiter::rules::RuleSet;
+
+use crate::rate_limiter_service::RateLimiterService;
+
+/// Reloads the rules file into `service` on SIGHUP, and whenever its
+/// modification time changes if `poll_interval` is non-zero. A file that fails
+/// to parse is reported and the rules already in use are kept.
+pub async fn watch_rules(
+    service: RateLimiterService,
+    path: PathBuf,
+    fallback: Limit,
+    poll_interval: Duration,
+) -> std::io::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut last_modified = modified(&path);
+
+    // A zero period would panic; without polling only SIGHUP triggers a reload
+    let mut poll = tokio::time::interval(poll_interval.max(Duration::from_secs(1)));
+    poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                println!("🔄 SIGHUP received, reloading {}", path.display());
+            }
+            _ = poll.tick(), if !poll_interval.is_zero() => {
+                let current = modified(&path);
+                if current == last_modified {
+                    continue;
+                }
+                println!("🔄 {} changed, reloading", path.display());
+            }
+        }
+
+        last_modified = modified(&path);
+        match RuleSet::load(&path, &fallback) {
+            Ok(rules) => {
+                let count = rules.rule_count();
+                service.reload_rules(rules);
+                println!("📏 Reloaded {} rate limit rule(s)", count);
+            }
+            Err(err) => eprintln!("⚠️  Keeping current rules: {}", err),
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
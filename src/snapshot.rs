@@ -0,0 +1,154 @@
+/// Point-in-time copies of the limiter state, so quota survives a restart
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::algorithm::KeyState;
+use crate::clock::WallClock;
+use crate::store::RateLimitStore;
+
+// Version 2 keeps a list of states per key, one per layer
+const MAGIC: &[u8; 8] = b"RLSNAP02";
+
+/// Write every key in `store` to `path`, replacing the previous snapshot only
+/// once the new one is complete. `now` is the store clock's current reading.
+pub fn save(store: &dyn RateLimitStore, now: u64, path: impl AsRef<Path>) -> Result<usize, Box<dyn std::error::Error>> {
+    let path = path.as_ref();
+    // Clock readings only mean something to this process; the file holds wall-clock times
+    let clocks = WallClock::at(now);
+
+    // Encode in memory first so the store is not held up by disk writes
+    let mut count = 0;
+    let mut body = Vec::new();
+    let mut result = Ok(());
+    store.for_each(&mut |id, states| {
+        if result.is_err() {
+            return;
+        }
+        let mut states = states.to_vec();
+        for state in &mut states {
+            state.map_timestamps(|at| clocks.wall_time(at));
+        }
+        result = bincode::serialize_into(&mut body, &(id, &states));
+        count += 1;
+    });
+    result?;
+
+    let temporary = temporary_path(path);
+    let mut file = BufWriter::new(File::create(&temporary)?);
+    file.write_all(MAGIC)?;
+    file.write_all(&clocks.wall.to_le_bytes())?;
+    file.write_all(&(count as u64).to_le_bytes())?;
+    file.write_all(&body)?;
+    file.into_inner()?.sync_all()?;
+
+    fs::rename(&temporary, path)?;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(count)
+}
+
+/// Load the snapshot at `path` into `store`, returning how many keys it held.
+/// A missing file is not an error: there is nothing to restore on a first start.
+pub fn load(store: &dyn RateLimitStore, now: u64, path: impl AsRef<Path>) -> Result<usize, Box<dyn std::error::Error>> {
+    let path = path.as_ref();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(format!("failed to open snapshot {}: {}", path.display(), err).into()),
+    };
+    let mut reader = BufReader::new(file);
+
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(format!("{} is not a rate limiter snapshot", path.display()).into());
+    }
+    let _taken_at = read_u64(&mut reader)?;
+    let count = read_u64(&mut reader)?;
+
+    let clocks = WallClock::at(now);
+    for _ in 0..count {
+        let (id, mut states): (String, Vec<KeyState>) = bincode::deserialize_from(&mut reader)
+            .map_err(|err| format!("corrupt snapshot {}: {}", path.display(), err))?;
+        for state in &mut states {
+            state.map_timestamps(|at| clocks.clock_time(at));
+        }
+        store.restore(&id, states, now);
+    }
+
+    Ok(count as usize)
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::algorithm::{Algorithm, Limit};
+    use crate::store::MemoryStore;
+
+    /// A path of its own for each test, with nothing there yet
+    fn test_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("snapshot-test-{}-{}.bin", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn limits() -> Vec<Limit> {
+        let per_minute = |algorithm| Limit {
+            algorithm,
+            ..Limit::default()
+        };
+        vec![per_minute(Algorithm::SlidingWindowLog), per_minute(Algorithm::Gcra)]
+    }
+
+    #[test]
+    fn a_saved_store_loads_back_the_same() {
+        let path = test_path("round-trip");
+        let limits = limits();
+        let now = Duration::from_secs(1_000).as_nanos() as u64;
+
+        let store = MemoryStore::default();
+        store.charge("a", &limits, 3, now);
+        store.charge("b", &limits[..1], 7, now);
+        assert_eq!(save(&store, now, &path).unwrap(), 2);
+
+        let restored = MemoryStore::default();
+        assert_eq!(load(&restored, now, &path).unwrap(), 2);
+        assert_eq!(restored.key_count(), 2);
+        assert_eq!(restored.peek("a", &limits, 0, now).remaining, 7);
+        assert_eq!(restored.peek("b", &limits[..1], 0, now).remaining, 3);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_missing_snapshot_restores_nothing() {
+        let path = test_path("missing");
+        assert_eq!(load(&MemoryStore::default(), 0, &path).unwrap(), 0);
+    }
+
+    #[test]
+    fn other_files_are_not_loaded() {
+        let path = test_path("foreign");
+        fs::write(&path, b"RLSNAP01 and then some").unwrap();
+
+        assert!(load(&MemoryStore::default(), 0, &path).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,436 @@
+/// Storage for per-key limiter state, separate from the algorithms that update it
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use tokio::sync::Notify;
+
+use crate::algorithm::{Decision, KeyState, Limit};
+use crate::layers::{self, States};
+use crate::eviction::{Evicted, EvictionPolicy, EvictionStats};
+use crate::rules::RuleSet;
+use crate::wal::Wal;
+
+/// Prefix of keys charged against a limit sent with each request, such as
+/// CL.THROTTLE's, instead of a rule. Rule reloads leave these keys alone.
+pub const THROTTLE_KEY_PREFIX: &str = "cl.throttle:";
+
+/// Holds the state behind every key. Each method is one read-modify-write of a
+/// key, so a backend decides how to make it atomic: a lock, a transaction or a
+/// server-side script.
+pub trait RateLimitStore: Send + Sync {
+    /// Charge `tokens` to `id` under every layer in `limits`, or under none if
+    /// any layer denies it, starting from a fresh state if the key is new
+    fn charge(&self, id: &str, limits: &[Limit], tokens: u64, now: u64) -> Decision;
+
+    /// The decision `charge` would make, without changing anything
+    fn peek(&self, id: &str, limits: &[Limit], tokens: u64, now: u64) -> Decision;
+
+    /// Hand `tokens` back to `id`; a key that is no longer stored has nothing to refund
+    fn refund(&self, id: &str, limits: &[Limit], tokens: u64, now: u64);
+
+    /// Carry every key over from the rule it fell under in `previous` to its rule in `current`
+    fn rescale(&self, previous: &RuleSet, current: &RuleSet, now: u64);
+
+    /// Drop keys that no longer need to be kept, returning how many went in this pass
+    fn evict(&self, rules: &RuleSet, now: u64) -> Evicted;
+
+    /// Number of keys currently stored
+    fn key_count(&self) -> usize;
+
+    /// Call `visit` with every stored key, as when taking a snapshot
+    fn for_each(&self, visit: &mut dyn FnMut(&str, &[KeyState]));
+
+    /// Put back state saved earlier, one per layer, replacing whatever `id` has now
+    fn restore(&self, id: &str, states: Vec<KeyState>, now: u64);
+
+    /// Keys evicted since the store was created
+    fn eviction_stats(&self) -> Evicted {
+        Evicted::default()
+    }
+
+    /// Notified when the store wants an eviction pass before the next sweep is due
+    fn eviction_wanted(&self) -> Option<&Notify> {
+        None
+    }
+}
+
+/// A key's limiter state, one per layer of its rule, plus what eviction needs to know about it
+struct Bucket {
+    states: States,
+    last_seen: u64,
+    // Set on every charge and cleared by the CLOCK sweep; only unset keys are evicted
+    referenced: bool,
+}
+
+impl Bucket {
+    fn charge(&mut self, limits: &[Limit], tokens: u64, now: u64) -> Decision {
+        self.last_seen = now;
+        self.referenced = true;
+        layers::try_acquire(limits, &mut self.states, tokens, now)
+    }
+}
+
+/// Keys held in process memory; lost on restart unless snapshotted
+pub struct MemoryStore {
+    buckets: DashMap<Box<str>, Bucket>,
+    policy: EvictionPolicy,
+    // Woken when a new key finds the store at its cap; charges never evict themselves
+    over_capacity: Notify,
+    stats: EvictionStats,
+    // Where charges and refunds are recorded, if they must survive a crash
+    journal: Option<Arc<Wal>>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new(EvictionPolicy::default())
+    }
+}
+
+impl MemoryStore {
+    pub fn new(policy: EvictionPolicy) -> Self {
+        Self {
+            buckets: DashMap::new(),
+            policy,
+            over_capacity: Notify::new(),
+            stats: EvictionStats::default(),
+            journal: None,
+        }
+    }
+
+    /// Record every charge and refund in `wal`
+    pub fn with_journal(mut self, wal: Arc<Wal>) -> Self {
+        self.journal = Some(wal);
+        self
+    }
+
+    fn record(&self, id: &str, states: &[KeyState]) {
+        if let Some(journal) = &self.journal {
+            journal.append(id, states);
+        }
+    }
+
+    /// Evict down to `target` keys with CLOCK: a referenced key loses its bit
+    /// and survives the pass, so recently used keys get a second chance
+    fn evict_least_recently_used(&self, target: usize) -> u64 {
+        let mut evicted = 0;
+
+        // The first pass clears every bit it spares, so the second always makes
+        // progress. Charges add keys meanwhile, so the excess is measured
+        // again before every pass.
+        loop {
+            let mut excess = self.buckets.len().saturating_sub(target);
+            if excess == 0 {
+                break;
+            }
+            self.buckets.retain(|_, bucket| {
+                if excess == 0 {
+                    true
+                } else if bucket.referenced {
+                    bucket.referenced = false;
+                    true
+                } else {
+                    excess -= 1;
+                    evicted += 1;
+                    false
+                }
+            });
+        }
+
+        self.stats.record(&Evicted {
+            over_capacity: evicted,
+            ..Evicted::default()
+        });
+        evicted
+    }
+
+    /// Ask the sweeper for an early pass once a new key reaches the cap. The
+    /// scan runs there, off the request path, so the count can briefly pass it.
+    fn note_new_key(&self) {
+        if self.policy.max_keys.is_some_and(|max_keys| self.buckets.len() >= max_keys) {
+            self.over_capacity.notify_one();
+        }
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn charge(&self, id: &str, limits: &[Limit], tokens: u64, now: u64) -> Decision {
+        // Existing keys are updated in place without allocating a new key
+        let mut bucket = match self.buckets.get_mut(id) {
+            Some(bucket) => bucket,
+            None => {
+                self.note_new_key();
+                self.buckets.entry(id.into()).or_insert_with(|| Bucket {
+                    states: layers::new_states(limits, now),
+                    last_seen: now,
+                    referenced: true,
+                })
+            }
+        };
+
+        let decision = bucket.charge(limits, tokens, now);
+        // Denials spend nothing, so there is nothing to lose in a crash
+        if decision.allowed {
+            self.record(id, &bucket.states);
+        }
+        decision
+    }
+
+    fn peek(&self, id: &str, limits: &[Limit], tokens: u64, now: u64) -> Decision {
+        // Unknown keys are judged against a fresh state that is never stored
+        match self.buckets.get(id) {
+            Some(bucket) => layers::peek(limits, &bucket.states, tokens, now),
+            None => layers::peek(limits, &layers::new_states(limits, now), tokens, now),
+        }
+    }
+
+    fn refund(&self, id: &str, limits: &[Limit], tokens: u64, now: u64) {
+        if let Some(mut bucket) = self.buckets.get_mut(id) {
+            layers::refund(limits, &mut bucket.states, tokens, now);
+            self.record(id, &bucket.states);
+        }
+    }
+
+    fn rescale(&self, previous: &RuleSet, current: &RuleSet, now: u64) {
+        for mut entry in self.buckets.iter_mut() {
+            let (id, bucket) = entry.pair_mut();
+            if id.starts_with(THROTTLE_KEY_PREFIX) {
+                continue;
+            }
+            let old_limits = &previous.resolve(id).limits;
+            layers::rescale(old_limits, &current.resolve(id).limits, &mut bucket.states, now);
+        }
+    }
+
+    fn evict(&self, rules: &RuleSet, now: u64) -> Evicted {
+        let idle_ttl = self.policy.idle_ttl.map(|ttl| ttl.as_nanos() as u64);
+        let mut evicted = Evicted::default();
+
+        self.buckets.retain(|id, bucket| {
+            if layers::is_replenished(&rules.resolve(id).limits, &bucket.states, now) {
+                evicted.replenished += 1;
+                false
+            } else if idle_ttl.is_some_and(|ttl| now.saturating_sub(bucket.last_seen) > ttl) {
+                evicted.idle += 1;
+                false
+            } else {
+                true
+            }
+        });
+        self.stats.record(&evicted);
+
+        if let Some(max_keys) = self.policy.max_keys {
+            // Evict a little extra so the next new key does not call for another pass
+            evicted.over_capacity = self.evict_least_recently_used(max_keys - max_keys / 20);
+        }
+
+        evicted
+    }
+
+    fn key_count(&self) -> usize {
+        self.buckets.len()
+    }
+
+    fn for_each(&self, visit: &mut dyn FnMut(&str, &[KeyState])) {
+        for entry in self.buckets.iter() {
+            visit(entry.key(), &entry.value().states);
+        }
+    }
+
+    fn restore(&self, id: &str, states: Vec<KeyState>, now: u64) {
+        self.buckets.insert(
+            id.into(),
+            Bucket {
+                states: states.into(),
+                last_seen: now,
+                // Not charged since the restart, so first in line for eviction
+                referenced: false,
+            },
+        );
+    }
+
+    fn eviction_stats(&self) -> Evicted {
+        self.stats.snapshot()
+    }
+
+    fn eviction_wanted(&self) -> Option<&Notify> {
+        Some(&self.over_capacity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::algorithm::Algorithm;
+    use crate::clock::{Clock, ManualClock};
+
+    fn clock() -> ManualClock {
+        let clock = ManualClock::new();
+        clock.set(Duration::from_secs(1_000));
+        clock
+    }
+
+    fn per_second(algorithm: Algorithm, limit: u64) -> Vec<Limit> {
+        vec![Limit {
+            algorithm,
+            limit,
+            window: Duration::from_secs(1),
+            burst: limit,
+            ..Limit::default()
+        }]
+    }
+
+    #[test]
+    fn sweeps_evict_replenished_and_idle_keys() {
+        let clock = clock();
+        let store = MemoryStore::new(EvictionPolicy {
+            idle_ttl: Some(Duration::from_secs(60)),
+            ..EvictionPolicy::default()
+        });
+        let limits = per_second(Algorithm::TokenBucket, 5);
+        let rules = RuleSet::single(limits[0].clone());
+        let mut slow = per_second(Algorithm::TokenBucket, 1);
+        slow[0].window = Duration::from_secs(3600);
+
+        store.charge("fast", &limits, 5, clock.now());
+        store.charge("slow", &slow, 1, clock.now());
+        assert_eq!(store.evict(&rules, clock.now()), Evicted::default());
+
+        // "slow" is judged by the rule set, under which it refills in a second too
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(store.evict(&rules, clock.now()).replenished, 2);
+
+        let rules = RuleSet::single(slow[0].clone());
+        store.charge("slow", &slow, 1, clock.now());
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(store.evict(&rules, clock.now()).idle, 1);
+        assert_eq!(store.key_count(), 0);
+        assert_eq!(store.eviction_stats().total(), 3);
+    }
+
+    /// Whether a pass has been asked for since the last call
+    fn eviction_wanted(store: &MemoryStore) -> bool {
+        let notified = store.eviction_wanted().unwrap().notified();
+        tokio::pin!(notified);
+        notified.enable()
+    }
+
+    #[test]
+    fn the_key_cap_evicts_keys_not_charged_since_the_last_pass() {
+        let clock = clock();
+        let store = MemoryStore::new(EvictionPolicy {
+            max_keys: Some(2),
+            ..EvictionPolicy::default()
+        });
+        let limits = per_second(Algorithm::TokenBucket, 5);
+        let rules = RuleSet::single(limits[0].clone());
+        let remaining = |id: &str| store.peek(id, &limits, 0, clock.now()).remaining;
+
+        // Charges only ask for a pass; none of them evicts
+        let mut states = layers::new_states(&limits, clock.now());
+        layers::try_acquire(&limits, &mut states, 1, clock.now());
+        store.restore("restored", states.to_vec(), clock.now());
+        store.charge("a", &limits, 1, clock.now());
+        assert!(!eviction_wanted(&store));
+        store.charge("b", &limits, 1, clock.now());
+        assert!(eviction_wanted(&store));
+        assert_eq!(store.key_count(), 3);
+
+        // Restored keys have not been charged since, so they go first
+        assert_eq!(store.evict(&rules, clock.now()).over_capacity, 1);
+        assert_eq!(store.key_count(), 2);
+        assert_eq!(remaining("restored"), 5);
+
+        // Once every key has been charged, one of them still has to go
+        store.charge("c", &limits, 1, clock.now());
+        assert!(eviction_wanted(&store));
+        assert_eq!(store.evict(&rules, clock.now()).over_capacity, 1);
+        assert_eq!(store.key_count(), 2);
+        assert_eq!(remaining("a") + remaining("b") + remaining("c"), 4 + 4 + 5);
+        assert_eq!(store.eviction_stats().over_capacity, 2);
+    }
+
+    #[test]
+    fn charges_follow_the_clock() {
+        let clock = clock();
+        let store = MemoryStore::default();
+        let limits = per_second(Algorithm::TokenBucket, 5);
+
+        for _ in 0..5 {
+            assert!(store.charge("a", &limits, 1, clock.now()).allowed);
+        }
+        let denied = store.charge("a", &limits, 1, clock.now());
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Duration::from_millis(200));
+        // Other keys have their own quota
+        assert!(store.charge("b", &limits, 1, clock.now()).allowed);
+
+        clock.advance(Duration::from_millis(200));
+        assert!(store.charge("a", &limits, 1, clock.now()).allowed);
+        assert!(!store.charge("a", &limits, 1, clock.now()).allowed);
+    }
+
+    #[test]
+    fn peek_and_refund_leave_the_key_consistent() {
+        let clock = clock();
+        let store = MemoryStore::default();
+        let limits = per_second(Algorithm::SlidingWindowLog, 5);
+
+        // Peeking at an unknown key stores nothing
+        assert_eq!(store.peek("a", &limits, 1, clock.now()).remaining, 5);
+        assert_eq!(store.key_count(), 0);
+
+        store.charge("a", &limits, 4, clock.now());
+        assert!(!store.peek("a", &limits, 2, clock.now()).allowed);
+        store.refund("a", &limits, 3, clock.now());
+        assert_eq!(store.peek("a", &limits, 0, clock.now()).remaining, 4);
+
+        // Refunds to keys that are gone are dropped
+        store.refund("b", &limits, 3, clock.now());
+        assert_eq!(store.key_count(), 1);
+    }
+
+    #[test]
+    fn layered_charges_are_all_or_nothing() {
+        let clock = clock();
+        let store = MemoryStore::default();
+        let mut limits = per_second(Algorithm::TokenBucket, 10);
+        limits.extend(per_second(Algorithm::Calendar, 3));
+
+        assert!(store.charge("a", &limits, 2, clock.now()).allowed);
+        let denied = store.charge("a", &limits, 2, clock.now());
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 1);
+        // The first layer was not charged for the denied request
+        assert_eq!(store.peek("a", &limits[..1], 0, clock.now()).remaining, 8);
+    }
+
+    #[test]
+    fn single_layer_keys_are_stored_inline() {
+        assert_eq!(std::mem::size_of::<States>(), std::mem::size_of::<KeyState>());
+        let limits = per_second(Algorithm::Gcra, 5);
+        assert!(matches!(layers::new_states(&limits, 0), States::Single(_)));
+    }
+
+    #[test]
+    fn rescale_follows_rule_changes_but_leaves_throttle_keys() {
+        let clock = clock();
+        let store = MemoryStore::default();
+        let before = per_second(Algorithm::TokenBucket, 10);
+        let after = per_second(Algorithm::TokenBucket, 4);
+        let throttle_key = format!("{}a", THROTTLE_KEY_PREFIX);
+
+        store.charge("a", &before, 1, clock.now());
+        store.charge(&throttle_key, &before, 1, clock.now());
+        store.rescale(
+            &RuleSet::single(before[0].clone()),
+            &RuleSet::single(after[0].clone()),
+            clock.now(),
+        );
+
+        assert_eq!(store.peek("a", &after, 0, clock.now()).remaining, 4);
+        assert_eq!(store.peek(&throttle_key, &before, 0, clock.now()).remaining, 9);
+    }
+}
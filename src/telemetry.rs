@@ -0,0 +1,125 @@
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{BufWriter, Write};
+use std::pin::Pin;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use opentelemetry::sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::trace::{self as sdktrace, TracerProvider};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::{Status, TraceContextExt, TraceError};
+use opentelemetry::{global, Context, KeyValue, Value};
+use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
+use serde_json::{json, Map};
+use tonic::Request;
+
+use rust_rate_limiter::config::ServerConfig;
+
+/// Install the tracer provider the gRPC tracing layer reports to: an OTLP
+/// exporter when a collector endpoint is configured, a JSON lines file when a
+/// trace file is, or both. Returns whether spans are exported at all.
+pub fn init(config: &ServerConfig) -> Result<bool, Box<dyn std::error::Error>> {
+    if config.otlp_endpoint.is_none() && config.trace_file.is_none() {
+        return Ok(false);
+    }
+
+    let resource = Resource::new([KeyValue::new("service.name", env!("CARGO_PKG_NAME"))]);
+    let mut provider = TracerProvider::builder().with_config(sdktrace::config().with_resource(resource));
+
+    if let Some(endpoint) = &config.otlp_endpoint {
+        let exporter = opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint);
+        let exporter = SpanExporterBuilder::from(exporter).build_span_exporter()?;
+        provider = provider.with_batch_exporter(exporter, opentelemetry::runtime::Tokio);
+        println!("🔭 Exporting spans to {}", endpoint);
+    }
+    if let Some(path) = &config.trace_file {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        provider = provider.with_batch_exporter(FileExporter::new(file), opentelemetry::runtime::Tokio);
+        println!("🔭 Writing spans to {}", path);
+    }
+
+    global::set_tracer_provider(provider.build());
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    Ok(true)
+}
+
+/// Export the spans still queued; call once the server has stopped
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// The span the tracing layer opened for this call, if it is being recorded.
+/// Call before `into_inner`, which drops the request's extensions.
+pub fn recording_span<T>(request: &Request<T>) -> Option<Context> {
+    request
+        .extensions()
+        .get::<Context>()
+        .filter(|cx| cx.span().is_recording())
+        .cloned()
+}
+
+/// Writes each finished span as a line of JSON, for tests and for running
+/// without a collector
+#[derive(Debug)]
+struct FileExporter {
+    file: BufWriter<File>,
+}
+
+impl FileExporter {
+    fn new(file: File) -> Self {
+        Self {
+            file: BufWriter::new(file),
+        }
+    }
+
+    fn write(&mut self, batch: Vec<SpanData>) -> std::io::Result<()> {
+        for span in batch {
+            let attributes: Map<_, _> = span
+                .attributes
+                .iter()
+                .map(|(key, value)| (key.to_string(), json_value(value)))
+                .collect();
+            let status = match &span.status {
+                Status::Unset => json!({ "code": "unset" }),
+                Status::Ok => json!({ "code": "ok" }),
+                Status::Error { description } => json!({ "code": "error", "description": description }),
+            };
+
+            let line = json!({
+                "trace_id": span.span_context.trace_id().to_string(),
+                "span_id": span.span_context.span_id().to_string(),
+                "parent_span_id": span.parent_span_id.to_string(),
+                "name": span.name,
+                "kind": format!("{:?}", span.span_kind).to_lowercase(),
+                "start_time_unix_nano": unix_nanos(span.start_time),
+                "end_time_unix_nano": unix_nanos(span.end_time),
+                "attributes": attributes,
+                "status": status,
+            });
+            serde_json::to_writer(&mut self.file, &line)?;
+            self.file.write_all(b"\n")?;
+        }
+        self.file.flush()
+    }
+}
+
+impl SpanExporter for FileExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> Pin<Box<dyn Future<Output = ExportResult> + Send>> {
+        let result = self.write(batch).map_err(|err| TraceError::Other(Box::new(err)));
+        Box::pin(std::future::ready(result))
+    }
+}
+
+fn json_value(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Bool(value) => json!(value),
+        Value::I64(value) => json!(value),
+        Value::F64(value) => json!(value),
+        value => json!(value.to_string()),
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos() as u64)
+}
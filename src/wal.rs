@@ -0,0 +1,392 @@
+/// Append-only log of key state changes, so quota spent since the last
+/// snapshot survives a crash
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::algorithm::KeyState;
+use crate::clock::WallClock;
+use crate::store::RateLimitStore;
+
+const SEGMENT_PREFIX: &str = "wal-";
+const SEGMENT_SUFFIX: &str = ".log";
+// Larger lengths can only come from a corrupt header
+const MAX_RECORD_LEN: usize = 1 << 24;
+
+/// Records the state a key was left in after each change. Records are buffered
+/// by `append` and made durable by `commit`; callers that commit while another
+/// commit is writing are covered by the next write, so concurrent requests
+/// share one fsync.
+///
+/// The log lives in numbered segment files. `rotate` starts a new segment so
+/// that older ones can be deleted once a snapshot covers them.
+pub struct Wal {
+    dir: PathBuf,
+    clocks: WallClock,
+    pending: Mutex<Pending>,
+    // Sequence number of the last record known to be on disk
+    durable: AtomicU64,
+    // Held by whichever caller is writing; everyone else waits for it
+    flushing: tokio::sync::Mutex<()>,
+    segment: Arc<Mutex<Segment>>,
+}
+
+#[derive(Default)]
+struct Pending {
+    buffer: Vec<u8>,
+    // Sequence number of the last record appended
+    appended: u64,
+}
+
+struct Segment {
+    file: File,
+    number: u64,
+    // Set when a write failed part way, leaving a torn record at the end
+    torn: bool,
+}
+
+impl Wal {
+    /// Open the log in `dir`, creating it if needed, and start a new segment
+    /// after any left by a previous run. `now` is the store clock's current reading.
+    pub fn open(dir: impl AsRef<Path>, now: u64) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let number = segments(&dir)?.last().map_or(0, |&(number, _)| number + 1);
+        let segment = Segment::create(&dir, number)?;
+
+        Ok(Self {
+            dir,
+            clocks: WallClock::at(now),
+            pending: Mutex::new(Pending::default()),
+            durable: AtomicU64::new(0),
+            flushing: tokio::sync::Mutex::new(()),
+            segment: Arc::new(Mutex::new(segment)),
+        })
+    }
+
+    /// Apply every record left by previous runs to `store`, oldest first,
+    /// returning how many were replayed. A record cut short by a crash ends its
+    /// segment; it was never committed, so no caller was told it succeeded.
+    pub fn replay(&self, store: &dyn RateLimitStore, now: u64) -> Result<usize, Box<dyn std::error::Error>> {
+        let current = self.lock_segment().number;
+        let clocks = WallClock::at(now);
+        let mut replayed = 0;
+
+        for (number, path) in segments(&self.dir)? {
+            if number >= current {
+                break;
+            }
+
+            let mut reader = BufReader::new(File::open(&path)?);
+            while let Some(record) = read_record(&mut reader)? {
+                let (id, mut states): (String, Vec<KeyState>) = match bincode::deserialize(&record) {
+                    Ok(entry) => entry,
+                    Err(_) => break,
+                };
+                for state in &mut states {
+                    state.map_timestamps(|at| clocks.clock_time(at));
+                }
+                store.restore(&id, states, now);
+                replayed += 1;
+            }
+        }
+
+        Ok(replayed)
+    }
+
+    /// Buffer a record of `id` now being in `states`. Call this while the key is
+    /// still locked, so records for one key are appended in the order they happened.
+    pub fn append(&self, id: &str, states: &[KeyState]) {
+        let mut states = states.to_vec();
+        for state in &mut states {
+            state.map_timestamps(|at| self.clocks.wall_time(at));
+        }
+        let record = match bincode::serialize(&(id, &states)) {
+            Ok(record) => record,
+            Err(err) => {
+                tracing::warn!(key = id, error = %err, "failed to encode write-ahead log record");
+                return;
+            }
+        };
+
+        let mut pending = self.lock_pending();
+        pending.buffer.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        pending.buffer.extend_from_slice(&crc32fast::hash(&record).to_le_bytes());
+        pending.buffer.extend_from_slice(&record);
+        pending.appended += 1;
+    }
+
+    /// Wait until every record appended so far is on disk
+    pub async fn commit(&self) -> io::Result<()> {
+        let target = self.lock_pending().appended;
+
+        while self.durable.load(Ordering::Acquire) < target {
+            let _flushing = self.flushing.lock().await;
+            // Whoever held the lock before us may have written our records already
+            if self.durable.load(Ordering::Acquire) >= target {
+                break;
+            }
+            self.flush().await?;
+            // Nothing was left to write, yet our records are not on disk
+            if self.durable.load(Ordering::Acquire) < target {
+                return Err(io::Error::other("WAL records were lost before reaching disk"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finish the current segment and start a new one, returning the new
+    /// segment's number. Everything in earlier segments is reflected in the
+    /// store by the time this returns, so a snapshot taken afterwards covers them.
+    pub async fn rotate(&self) -> io::Result<u64> {
+        let _flushing = self.flushing.lock().await;
+        self.flush().await?;
+
+        let segment = self.segment.clone();
+        let dir = self.dir.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut segment = segment.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            *segment = Segment::create(&dir, segment.number + 1)?;
+            Ok(segment.number)
+        })
+        .await?
+    }
+
+    /// Delete the segments before `number`, once a snapshot has made them redundant
+    pub fn remove_segments_before(&self, number: u64) -> io::Result<usize> {
+        let mut removed = 0;
+        for (segment, path) in segments(&self.dir)? {
+            if segment < number {
+                fs::remove_file(path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Write out and sync everything buffered. Callers hold `flushing`. If the
+    /// write fails the records go back in the buffer, ahead of any appended
+    /// since, so `durable` never covers a record that is not on disk.
+    async fn flush(&self) -> io::Result<()> {
+        let (buffer, appended) = {
+            let mut pending = self.lock_pending();
+            (std::mem::take(&mut pending.buffer), pending.appended)
+        };
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        let segment = self.segment.clone();
+        let dir = self.dir.clone();
+        let (buffer, written) = tokio::task::spawn_blocking(move || {
+            let mut segment = segment.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let written = segment.write(&dir, &buffer);
+            (buffer, written)
+        })
+        .await?;
+
+        if let Err(err) = written {
+            self.lock_pending().buffer.splice(0..0, buffer);
+            return Err(err);
+        }
+        self.durable.store(appended, Ordering::Release);
+        Ok(())
+    }
+
+    fn lock_pending(&self) -> std::sync::MutexGuard<'_, Pending> {
+        self.pending.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock_segment(&self) -> std::sync::MutexGuard<'_, Segment> {
+        self.segment.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Segment {
+    fn create(dir: &Path, number: u64) -> io::Result<Self> {
+        let path = dir.join(format!("{}{:020}{}", SEGMENT_PREFIX, number, SEGMENT_SUFFIX));
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        // Make the new file's directory entry durable too
+        File::open(dir)?.sync_all()?;
+        Ok(Self {
+            file,
+            number,
+            torn: false,
+        })
+    }
+
+    /// Append `buffer` and sync it. Replay stops at a torn record, so after a
+    /// failed write nothing more goes in this segment; the next write starts
+    /// a new one.
+    fn write(&mut self, dir: &Path, buffer: &[u8]) -> io::Result<()> {
+        if self.torn {
+            *self = Segment::create(dir, self.number + 1)?;
+        }
+        let written = self.file.write_all(buffer).and_then(|()| self.file.sync_data());
+        self.torn = written.is_err();
+        written
+    }
+}
+
+/// Segment files in `dir`, oldest first
+fn segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let number = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(SEGMENT_PREFIX)?.strip_suffix(SEGMENT_SUFFIX))
+            .and_then(|number| number.parse().ok());
+        if let Some(number) = number {
+            segments.push((number, path));
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// The next record's payload, or None at the end of the segment or a torn write
+fn read_record(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0; 8];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+    if len > MAX_RECORD_LEN {
+        return Ok(None);
+    }
+
+    let mut record = vec![0; len];
+    match reader.read_exact(&mut record) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    Ok((crc32fast::hash(&record) == checksum).then_some(record))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithm::Limit;
+    use crate::store::MemoryStore;
+
+    /// An empty directory of its own for each test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wal-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn charged(limit: &Limit, tokens: u64, now: u64) -> Vec<KeyState> {
+        let mut state = limit.new_state(now);
+        limit.try_acquire(&mut state, tokens, now);
+        vec![state]
+    }
+
+    fn remaining(store: &MemoryStore, id: &str, limit: &Limit, now: u64) -> u64 {
+        store.peek(id, std::slice::from_ref(limit), 0, now).remaining
+    }
+
+    #[tokio::test]
+    async fn replay_restores_committed_records() {
+        let dir = test_dir("replay");
+        let limit = Limit::default();
+        {
+            let wal = Wal::open(&dir, 0).unwrap();
+            wal.append("a", &charged(&limit, 3, 0));
+            wal.append("b", &charged(&limit, 5, 0));
+            // A later record for the same key wins
+            wal.append("a", &charged(&limit, 4, 0));
+            wal.commit().await.unwrap();
+        }
+
+        let wal = Wal::open(&dir, 0).unwrap();
+        let store = MemoryStore::default();
+        assert_eq!(wal.replay(&store, 0).unwrap(), 3);
+        assert_eq!(remaining(&store, "a", &limit, 0), 6);
+        assert_eq!(remaining(&store, "b", &limit, 0), 5);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_stops_at_a_torn_record() {
+        let dir = test_dir("torn");
+        let limit = Limit::default();
+        {
+            let wal = Wal::open(&dir, 0).unwrap();
+            wal.append("a", &charged(&limit, 3, 0));
+            wal.append("b", &charged(&limit, 5, 0));
+            wal.commit().await.unwrap();
+        }
+
+        // Cut the last record short, as a crash part way through a write would
+        let (_, path) = segments(&dir).unwrap().pop().unwrap();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(file.metadata().unwrap().len() - 3).unwrap();
+
+        let wal = Wal::open(&dir, 0).unwrap();
+        let store = MemoryStore::default();
+        assert_eq!(wal.replay(&store, 0).unwrap(), 1);
+        assert_eq!(remaining(&store, "a", &limit, 0), 7);
+        assert_eq!(store.key_count(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_stops_at_a_record_with_a_bad_checksum() {
+        let dir = test_dir("checksum");
+        let limit = Limit::default();
+        {
+            let wal = Wal::open(&dir, 0).unwrap();
+            wal.append("a", &charged(&limit, 3, 0));
+            wal.commit().await.unwrap();
+        }
+
+        let (_, path) = segments(&dir).unwrap().pop().unwrap();
+        let mut contents = fs::read(&path).unwrap();
+        *contents.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, contents).unwrap();
+
+        let wal = Wal::open(&dir, 0).unwrap();
+        assert_eq!(wal.replay(&MemoryStore::default(), 0).unwrap(), 0);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn segments_before_a_rotation_can_be_compacted_away() {
+        let dir = test_dir("compaction");
+        let limit = Limit::default();
+        {
+            let wal = Wal::open(&dir, 0).unwrap();
+            wal.append("a", &charged(&limit, 3, 0));
+            wal.commit().await.unwrap();
+
+            // Once a snapshot covers everything before the rotation, those segments go
+            let next = wal.rotate().await.unwrap();
+            wal.append("b", &charged(&limit, 5, 0));
+            wal.commit().await.unwrap();
+            assert_eq!(wal.remove_segments_before(next).unwrap(), 1);
+        }
+
+        let wal = Wal::open(&dir, 0).unwrap();
+        let store = MemoryStore::default();
+        assert_eq!(wal.replay(&store, 0).unwrap(), 1);
+        assert_eq!(store.key_count(), 1);
+        assert_eq!(remaining(&store, "b", &limit, 0), 5);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}